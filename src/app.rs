@@ -1,9 +1,10 @@
 use std::env;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use clap::{crate_authors, crate_name, crate_version, App, Arg, ArgMatches};
-use failure::ResultExt;
-use log::info;
+use failure::{format_err, ResultExt};
+use log::{debug, info};
 use rusoto_core::credential::ProfileProvider;
 use rusoto_core::{HttpClient, Region};
 use rusoto_logs::CloudWatchLogsClient;
@@ -14,9 +15,36 @@ use crate::errors;
 
 const DEFAULT_REGION: &str = "ap-northeast-1";
 
+/// `--region` が省略されたときに参照する環境変数 (優先度順)
+const REGION_ENV_VARS: &[&str] = &["AWS_REGION", "AWS_DEFAULT_REGION"];
+
+const REGION_NAMES: &[&str] = &[
+    "ap-northeast-1",
+    "ap-northeast-2",
+    "ap-south-1",
+    "ap-southeast-1",
+    "ap-southeast-2",
+    "ca-central-1",
+    "eu-central-1",
+    "eu-west-1",
+    "eu-west-2",
+    "eu-west-3",
+    "sa-east-1",
+    "us-east-1",
+    "us-east-2",
+    "us-west-1",
+    "us-west-2",
+    "us-gov-west-1",
+    "cn-north-1",
+    "cn-northwest-1",
+];
+
+/// 候補として提示するリージョン名の最大編集距離
+const MAX_REGION_DISTANCE: usize = 4;
+
 struct GlobalOptions {
-    profile: String,
-    region: Region,
+    profiles: Vec<String>,
+    regions: Vec<Region>,
     role_arn: Option<String>,
     mfa_serial: Option<String>,
 }
@@ -25,29 +53,107 @@ fn matches_string(matches: &ArgMatches<'static>, key: &str) -> Option<String> {
     matches.value_of(key).map(|s| s.to_string())
 }
 
-impl From<&ArgMatches<'static>> for GlobalOptions {
-    fn from(matches: &ArgMatches<'static>) -> Self {
-        GlobalOptions {
-            profile: matches_string(matches, "PROFILE").unwrap(),
-            region: Region::from_str(matches.value_of("REGION").unwrap_or(DEFAULT_REGION))
-                .expect("Wrong region name"),
+fn similar_region_names(name: &str) -> Vec<&'static str> {
+    let name = name.to_lowercase();
+    let mut candidates: Vec<(usize, &'static str)> = REGION_NAMES
+        .iter()
+        .map(|region| (strsim::levenshtein(name.as_str(), region), *region))
+        .filter(|(distance, _)| *distance <= MAX_REGION_DISTANCE)
+        .collect();
+    candidates.sort();
+
+    match candidates.first() {
+        Some((nearest, _)) => {
+            let nearest = *nearest;
+            candidates
+                .into_iter()
+                .take_while(|(distance, _)| *distance == nearest)
+                .map(|(_, region)| region)
+                .collect()
+        }
+        None => Vec::new(),
+    }
+}
+
+fn invalid_region_message(name: &str) -> String {
+    let suggestions = similar_region_names(name);
+    if suggestions.is_empty() {
+        format!("'{}' is not a valid region name", name)
+    } else {
+        format!(
+            "'{}' is not a valid region name. Did you mean {}?",
+            name,
+            suggestions
+                .iter()
+                .map(|s| format!("'{}'", s))
+                .collect::<Vec<_>>()
+                .join(" or ")
+        )
+    }
+}
+
+pub fn validate_region(name: String) -> Result<(), String> {
+    Region::from_str(name.as_str())
+        .map(|_| ())
+        .map_err(|_| invalid_region_message(name.as_str()))
+}
+
+pub fn parse_region(name: &str, source: &str) -> Result<Region, errors::Error> {
+    debug!("region: {} (from {})", name, source);
+
+    let region = Region::from_str(name)
+        .map_err(|_| format_err!("{} (from {})", invalid_region_message(name), source))
+        .context(errors::ErrorKind::InvalidRegion)?;
+
+    Ok(region)
+}
+
+fn resolve_regions(matches: &ArgMatches<'static>) -> Result<Vec<Region>, errors::Error> {
+    // --region > AWS_REGION > AWS_DEFAULT_REGION > デフォルト の順で決める
+    if let Some(names) = matches.values_of("REGION") {
+        return names.map(|name| parse_region(name, "--region")).collect();
+    }
+
+    let (source, name) = REGION_ENV_VARS
+        .iter()
+        .filter_map(|key| env::var(key).ok().map(|value| (*key, value)))
+        .find(|(_, value)| !value.is_empty())
+        .unwrap_or(("default", DEFAULT_REGION.to_string()));
+
+    Ok(vec![parse_region(name.as_str(), source)?])
+}
+
+impl GlobalOptions {
+    fn from_matches(matches: &ArgMatches<'static>) -> Result<Self, errors::Error> {
+        Ok(GlobalOptions {
+            profiles: match matches.values_of("PROFILE") {
+                Some(profiles) => profiles.map(|s| s.to_string()).collect(),
+                None => vec![env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string())],
+            },
+            regions: resolve_regions(matches)?,
             role_arn: matches_string(matches, "ROLE_ARN"),
             mfa_serial: matches_string(matches, "MFA_SERIAL"),
-        }
+        })
     }
 }
 
-fn cwlogs_client(
+pub fn cwlogs_client(
     region: Region,
     profile_name: &str,
     role_arn: Option<&str>,
     external_id: Option<&str>,
     mfa_serial: Option<&str>,
 ) -> CloudWatchLogsClient {
-    env::set_var("AWS_PROFILE", profile_name);
+    // 複数のプロファイルを同時に扱うため、環境変数ではなくプロバイダに直接指定する
+    let mut profile_provider = ProfileProvider::new().expect("Cannot instantiate ProfileProvider");
+    profile_provider.set_profile(profile_name);
 
     if let Some(role_arn) = role_arn {
-        let sts = StsClient::new(region.clone());
+        let sts = StsClient::new_with(
+            HttpClient::new().expect("Cannot create HttpClient for STS service"),
+            profile_provider,
+            region.clone(),
+        );
         CloudWatchLogsClient::new_with(
             HttpClient::new().expect("Cannot create HttpClient for STS service"),
             StsAssumeRoleSessionCredentialsProvider::new(
@@ -64,7 +170,7 @@ fn cwlogs_client(
     } else {
         CloudWatchLogsClient::new_with(
             HttpClient::new().expect("Cannot create HttpClient for STS service"),
-            ProfileProvider::new().expect("Cannot instantiate ProfileProvider"),
+            profile_provider,
             region,
         )
     }
@@ -77,20 +183,40 @@ pub fn main() -> Result<(), errors::Error> {
     info!("match agruments");
     let matches = app.clone().get_matches();
 
-    let global_options = GlobalOptions::from(&matches);
+    let global_options = GlobalOptions::from_matches(&matches)?;
 
-    info!("create rusoto client");
-    let client = cwlogs_client(
-        global_options.region,
-        global_options.profile.as_str(),
-        global_options.role_arn.as_ref().map(|s| s.as_str()),
-        None,
-        global_options.mfa_serial.as_ref().map(|s| s.as_str()),
-    );
+    info!("create rusoto clients");
+    let mut targets = Vec::new();
+    for profile in global_options.profiles.iter() {
+        for region in global_options.regions.iter() {
+            targets.push(cmd::Target {
+                profile: profile.clone(),
+                region: region.clone(),
+                client: Arc::new(cwlogs_client(
+                    region.clone(),
+                    profile.as_str(),
+                    global_options.role_arn.as_ref().map(|s| s.as_str()),
+                    None,
+                    global_options.mfa_serial.as_ref().map(|s| s.as_str()),
+                )),
+            });
+        }
+    }
 
     info!("invoke commands");
     match matches.subcommand() {
-        ("get", Some(m)) => cmd::get::run(client, m),
+        ("get", Some(m)) => cmd::get::run(targets, m),
+        ("export", Some(m)) => cmd::export::run(targets, m),
+        ("search", Some(m)) => cmd::search::run(m),
+        ("put", Some(m)) => cmd::put::run(targets, m),
+        ("exec", Some(m)) => cmd::exec::run(targets, m),
+        ("import", Some(m)) => cmd::import::run(targets, m),
+        ("copy", Some(m)) => cmd::copy::run(targets, m),
+        ("group", Some(m)) => cmd::group::run(targets, m),
+        ("audit", Some(m)) => cmd::audit::run(targets, m),
+        ("metric-filters", Some(m)) => cmd::metric_filters::run(targets, m),
+        ("subscriptions", Some(m)) => cmd::subscriptions::run(targets, m),
+        ("export-task", Some(m)) => cmd::export_task::run(targets, m),
         _ => {
             app.print_help().context(errors::ErrorKind::Clap)?;
             Err(errors::Error::from(errors::ErrorKind::NoSubCommand))
@@ -105,19 +231,23 @@ fn app() -> App<'static, 'static> {
         .about("")
         .arg(
             Arg::with_name("PROFILE")
-                .help("AWS credentials profile")
+                .help("AWS credentials profile. Can be given multiple times to query several accounts. Defaults to AWS_PROFILE or 'default'")
                 .short("p")
                 .long("profile")
-                .required(true)
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .value_name("PROFILE"),
         )
         .arg(
             Arg::with_name("REGION")
-                .help("AWS region")
+                .help("AWS region. Can be given multiple times. Falls back to AWS_REGION, AWS_DEFAULT_REGION, then ap-northeast-1")
                 .short("r")
                 .long("region")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_region)
                 .value_name("REGION"),
         )
         .arg(
@@ -138,4 +268,15 @@ fn app() -> App<'static, 'static> {
     // TODO: アプリの情報を設定する
 
     app.subcommand(cmd::get::sub_command("get"))
+        .subcommand(cmd::export::sub_command("export"))
+        .subcommand(cmd::search::sub_command("search"))
+        .subcommand(cmd::put::sub_command("put"))
+        .subcommand(cmd::exec::sub_command("exec"))
+        .subcommand(cmd::import::sub_command("import"))
+        .subcommand(cmd::copy::sub_command("copy"))
+        .subcommand(cmd::group::sub_command("group"))
+        .subcommand(cmd::audit::sub_command("audit"))
+        .subcommand(cmd::metric_filters::sub_command("metric-filters"))
+        .subcommand(cmd::subscriptions::sub_command("subscriptions"))
+        .subcommand(cmd::export_task::sub_command("export-task"))
 }
@@ -1 +1,202 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use ansi_term::Color;
+use chrono::prelude::*;
+use failure::{format_err, ResultExt};
+use futures::prelude::*;
+use log::{debug, info};
+use rusoto_core::request::BufferedHttpResponse;
+use rusoto_core::Region;
+use rusoto_logs::CloudWatchLogsClient;
+
+use crate::errors;
+
+pub mod audit;
+pub mod copy;
+pub mod exec;
+pub mod export;
+pub mod export_task;
 pub mod get;
+pub mod group;
+pub mod import;
+pub mod metric_filters;
+pub mod put;
+pub mod search;
+pub mod subscriptions;
+
+/// コマンドの操作対象となるプロファイルとリージョンの組
+pub struct Target {
+    pub profile: String,
+    pub region: Region,
+    pub client: Arc<CloudWatchLogsClient>,
+}
+
+impl Target {
+    /// 複数のターゲットを扱うときに出力へ付与するラベル
+    pub fn label(&self) -> String {
+        format!("{}/{}", self.profile, self.region.name())
+    }
+}
+
+/// 1つのプロファイル・リージョンに対してのみ実行できるコマンド用にターゲットを取り出す
+pub fn single_target(targets: Vec<Target>) -> Result<Target, errors::Error> {
+    if targets.len() != 1 {
+        return Err(format_err!(
+            "This command accepts only one '--profile' and '--region'"
+        ))
+        .context(errors::ErrorKind::InsufficientArguments)?;
+    }
+
+    Ok(targets.into_iter().next().unwrap())
+}
+
+/// 取り消せない操作の前に確認する
+///
+/// `yes` が指定された場合は確認しない。端末から実行されていない場合は確認できないのでエラーとする。
+pub fn confirm(message: &str, yes: bool) -> Result<bool, errors::Error> {
+    if yes {
+        return Ok(true);
+    }
+    if !atty::is(atty::Stream::Stdin) {
+        return Err(format_err!(
+            "Cannot ask for confirmation without a terminal. Use '--yes' to proceed."
+        ))
+        .context(errors::ErrorKind::InsufficientArguments)?;
+    }
+
+    eprint!("{} [y/N] ", message);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    let accepted = ["y", "yes"].contains(&answer.trim().to_lowercase().as_str());
+    if !accepted {
+        eprintln!("Cancelled");
+    }
+    Ok(accepted)
+}
+
+/// エポックからのミリ秒をJSTの日時として表す
+pub fn format_epoch_millis(epoch_millis: i64) -> String {
+    Utc.timestamp_millis(epoch_millis)
+        .with_timezone(&*get::TZ_ASIA_TOKYO)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+/// 列幅を揃えて表を出力する。色付けが有効ならヘッダーを強調する。
+pub fn print_table(
+    headers: &[&str],
+    rows: &[Vec<String>],
+    enable_color: bool,
+) -> Result<(), errors::Error> {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    // 最後の列は幅を揃えず、行末に空白を残さない
+    let format_row = |cells: Vec<&str>| {
+        let last = cells.len() - 1;
+        cells
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| {
+                if i == last {
+                    cell.to_string()
+                } else {
+                    format!("{:width$}", cell, width = widths[i])
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let header = format_row(headers.to_vec());
+    if enable_color {
+        writeln!(out, "{}", Color::Green.paint(header))?;
+    } else {
+        writeln!(out, "{}", header)?;
+    }
+    for row in rows.iter() {
+        writeln!(
+            out,
+            "{}",
+            format_row(row.iter().map(|cell| cell.as_str()).collect())
+        )?;
+    }
+    Ok(())
+}
+
+/// 一時的なエラーを再試行する回数の上限
+const MAX_RETRIES: u32 = 5;
+
+/// スロットリングやサービス側の一時的なエラーの応答かどうか
+pub fn is_transient_response(res: &BufferedHttpResponse) -> bool {
+    res.status.is_server_error() || String::from_utf8_lossy(&res.body).contains("Throttling")
+}
+
+/// `is_transient` が真となるエラーの間は、待ち時間を倍にしながら `call` を再試行する
+pub fn retry_with_backoff<T, E, F>(is_transient: fn(&E) -> bool, mut call: F) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> Result<T, E>,
+{
+    let mut wait = Duration::from_millis(500);
+    let mut retries = 0;
+    loop {
+        match call() {
+            Err(ref e) if retries < MAX_RETRIES && is_transient(e) => {
+                debug!("retry in {:?}: {}", wait, e);
+                thread::sleep(wait);
+                wait *= 2;
+                retries += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Payload {
+    Done,
+    Failure(errors::Error),
+}
+
+/// tokioのランタイム上でフューチャーを完了まで実行し、その結果を返す
+pub fn run_future(
+    f: Box<dyn Future<Item = (), Error = errors::Error> + Send>,
+) -> Result<(), errors::Error> {
+    let (sender, receiver) = channel();
+    let sender_ok = sender.clone();
+    let sender_err = sender.clone();
+
+    let f = f
+        .map(move |_| {
+            sender_ok.send(Payload::Done).unwrap();
+        })
+        .map_err(move |e| {
+            sender_err.send(Payload::Failure(e)).unwrap();
+        });
+
+    info!("run!!");
+    tokio::run(f);
+
+    info!("receive result");
+    match receiver.recv().context(errors::ErrorKind::SyncChannel)? {
+        Payload::Done => Ok(()),
+        Payload::Failure(e) => {
+            debug!("error occurred: {}", e);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::{format_err, ResultExt};
+use log::info;
+use rusoto_logs::LogGroup;
+
+use crate::cmd::group::{self, RETENTION_DAYS};
+use crate::cmd::{self, get, Target};
+use crate::errors;
+
+pub struct AuditOptions<'a> {
+    prefix: Option<&'a str>,
+    max_retention: Option<i64>,
+    top: usize,
+    apply_retention: Option<i64>,
+    dry_run: bool,
+    yes: bool,
+}
+
+impl<'a> From<&'a ArgMatches<'a>> for AuditOptions<'a> {
+    fn from(matches: &'a ArgMatches<'a>) -> Self {
+        AuditOptions {
+            prefix: matches.value_of("PREFIX"),
+            max_retention: matches
+                .value_of("MAX_RETENTION")
+                .map(|s| s.parse().unwrap()),
+            top: get::matches_count(matches, "TOP").unwrap(),
+            apply_retention: matches
+                .value_of("APPLY_RETENTION")
+                .map(|s| s.parse().unwrap()),
+            dry_run: matches.is_present("DRY_RUN"),
+            yes: matches.is_present("YES"),
+        }
+    }
+}
+
+fn validate_days(s: String) -> Result<(), String> {
+    match group::parse_retention(s.as_str()) {
+        Some(Some(_)) => Ok(()),
+        _ => Err(format!(
+            "'{}' is not a valid retention. Use one of {}",
+            s,
+            RETENTION_DAYS
+                .iter()
+                .map(|days| days.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Report log groups without retention or encryption, and the largest ones")
+        .arg(
+            Arg::with_name("PREFIX")
+                .help("Audit only the log groups whose name starts with PREFIX")
+                .long("prefix")
+                .takes_value(true)
+                .value_name("PREFIX"),
+        )
+        .arg(
+            Arg::with_name("MAX_RETENTION")
+                .help("Report the log groups which keep the events longer than DAYS. Defaults to '--apply-retention'.")
+                .long("max-retention")
+                .takes_value(true)
+                .validator(validate_days)
+                .value_name("DAYS"),
+        )
+        .arg(
+            Arg::with_name("TOP")
+                .help("The number of the largest log groups to report")
+                .long("top")
+                .takes_value(true)
+                .default_value("10")
+                .validator(get::validate_count)
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("APPLY_RETENTION")
+                .help("Set the retention of the log groups without retention or above '--max-retention' to DAYS.")
+                .long("apply-retention")
+                .takes_value(true)
+                .validator(validate_days)
+                .value_name("DAYS"),
+        )
+        .arg(
+            Arg::with_name("DRY_RUN")
+                .help("Only show the changes '--apply-retention' would make.")
+                .long("dry-run")
+                .requires("APPLY_RETENTION"),
+        )
+        .arg(
+            Arg::with_name("YES")
+                .help("Do not ask for confirmation.")
+                .short("y")
+                .long("yes"),
+        )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Report
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// 取得元のターゲットの番号とロググループ
+struct AuditedGroup {
+    target: usize,
+    group: LogGroup,
+}
+
+impl AuditedGroup {
+    fn name(&self) -> &str {
+        self.group.log_group_name.as_deref().unwrap_or("")
+    }
+
+    /// 保持期間が未設定か、`max_retention` より長い
+    fn violates(&self, max_retention: Option<i64>) -> bool {
+        match (self.group.retention_in_days, max_retention) {
+            (None, _) => true,
+            (Some(days), Some(max)) => days > max,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// `1.5 GiB` のように読みやすい単位で表す
+fn format_bytes(bytes: i64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_retention(days: Option<i64>) -> String {
+    match days {
+        Some(days) => format!("{} days", days),
+        None => "never expire".to_string(),
+    }
+}
+
+struct Reporter<'a> {
+    targets: &'a [Target],
+}
+
+impl<'a> Reporter<'a> {
+    /// 複数のターゲットを扱う場合はロググループ名の前に取得元を付ける
+    fn group_label(&self, group: &AuditedGroup) -> String {
+        if self.targets.len() > 1 {
+            format!("{}\t{}", self.targets[group.target].label(), group.name())
+        } else {
+            group.name().to_string()
+        }
+    }
+
+    fn section(
+        &self,
+        title: &str,
+        groups: &[&AuditedGroup],
+        detail: &dyn Fn(&AuditedGroup) -> String,
+    ) {
+        println!("== {} ({}) ==", title, groups.len());
+        for group in groups.iter() {
+            println!("{}\t{}", self.group_label(group), detail(group));
+        }
+        println!();
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// run
+//
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("parse audit options");
+    let options = AuditOptions::from(matches);
+    let max_retention = options.max_retention.or(options.apply_retention);
+    if let (Some(apply), Some(max)) = (options.apply_retention, max_retention) {
+        if apply > max {
+            return Err(format_err!(
+                "'--apply-retention' must not be longer than '--max-retention'"
+            ))
+            .context(errors::ErrorKind::InsufficientArguments)?;
+        }
+    }
+
+    info!("describe log groups");
+    let mut groups = Vec::new();
+    for (i, target) in targets.iter().enumerate() {
+        for group in group::describe_log_groups(&target.client, options.prefix)? {
+            groups.push(AuditedGroup { target: i, group });
+        }
+    }
+
+    let reporter = Reporter { targets: &targets };
+    let stored_bytes = |group: &AuditedGroup| format_bytes(group.group.stored_bytes.unwrap_or(0));
+
+    let no_retention: Vec<&AuditedGroup> = groups
+        .iter()
+        .filter(|group| group.group.retention_in_days.is_none())
+        .collect();
+    reporter.section("No retention", &no_retention, &stored_bytes);
+
+    if let Some(max) = max_retention {
+        let above: Vec<&AuditedGroup> = groups
+            .iter()
+            .filter(|group| {
+                group
+                    .group
+                    .retention_in_days
+                    .into_iter()
+                    .any(|days| days > max)
+            })
+            .collect();
+        reporter.section(
+            format!("Retention above {} days", max).as_str(),
+            &above,
+            &|group| format_retention(group.group.retention_in_days),
+        );
+    }
+
+    let no_kms: Vec<&AuditedGroup> = groups
+        .iter()
+        .filter(|group| group.group.kms_key_id.is_none())
+        .collect();
+    reporter.section("No KMS key", &no_kms, &stored_bytes);
+
+    let mut largest: Vec<&AuditedGroup> = groups.iter().collect();
+    largest.sort_by_key(|group| -group.group.stored_bytes.unwrap_or(0));
+    largest.truncate(options.top);
+    reporter.section(
+        format!("Largest stored bytes (top {})", options.top).as_str(),
+        &largest,
+        &|group| {
+            format!(
+                "{}\t{}",
+                stored_bytes(group),
+                format_retention(group.group.retention_in_days)
+            )
+        },
+    );
+
+    let days = match options.apply_retention {
+        Some(days) => days,
+        None => return Ok(()),
+    };
+
+    // 保持期間が未設定、または方針より長いロググループを修正する
+    let violations: Vec<&AuditedGroup> = groups
+        .iter()
+        .filter(|group| group.violates(max_retention))
+        .collect();
+    if violations.is_empty() {
+        println!("No log groups to fix");
+        return Ok(());
+    }
+    if options.dry_run {
+        for group in violations.iter() {
+            println!(
+                "Would set the retention of {} from {} to {} days",
+                reporter.group_label(group),
+                format_retention(group.group.retention_in_days),
+                days
+            );
+        }
+        return Ok(());
+    }
+
+    let message = format!(
+        "Set the retention of {} log groups to {} days? Older events will be deleted.",
+        violations.len(),
+        days
+    );
+    if !cmd::confirm(message.as_str(), options.yes)? {
+        return Ok(());
+    }
+    for group in violations {
+        group::put_retention(&targets[group.target], group.name(), Some(days))?;
+    }
+    Ok(())
+}
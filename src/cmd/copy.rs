@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::{format_err, ResultExt};
+use futures::prelude::*;
+use log::info;
+use rusoto_logs::InputLogEvent;
+
+use crate::app;
+use crate::cmd::export::checkpoint::Checkpoint;
+use crate::cmd::get::event::LogEvent;
+use crate::cmd::get::{self, reader, stream};
+use crate::cmd::put::writer::{self, LogEventsWriter};
+use crate::cmd::{self, Target};
+use crate::errors;
+
+pub struct CopyOptions<'a> {
+    group_name: &'a str,
+    filter_expression: Option<&'a str>,
+    start_time: DateTime<Utc>,
+    end_time: Option<DateTime<Utc>>,
+    dest_group_name: &'a str,
+    dest_profile: Option<&'a str>,
+    dest_region: Option<&'a str>,
+    dest_role_arn: Option<&'a str>,
+    checkpoint: Option<PathBuf>,
+    restart: bool,
+    dry_run: bool,
+}
+
+impl<'a> From<&'a ArgMatches<'a>> for CopyOptions<'a> {
+    fn from(matches: &'a ArgMatches<'a>) -> Self {
+        CopyOptions {
+            group_name: matches.value_of("GROUP_NAME").unwrap(),
+            filter_expression: matches.value_of("FILTER_EXPRESSION"),
+            start_time: get::from_jst_text(matches.value_of("START_TIME").unwrap()),
+            end_time: matches.value_of("END_TIME").map(get::from_jst_text),
+            dest_group_name: matches.value_of("DEST_GROUP_NAME").unwrap(),
+            dest_profile: matches.value_of("DEST_PROFILE"),
+            dest_region: matches.value_of("DEST_REGION"),
+            dest_role_arn: matches.value_of("DEST_ROLE_ARN"),
+            checkpoint: matches.value_of("CHECKPOINT").map(PathBuf::from),
+            restart: matches.is_present("RESTART"),
+            dry_run: matches.is_present("DRY_RUN"),
+        }
+    }
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Copy the events of a log group to another log group, possibly in another account")
+        .arg(
+            Arg::with_name("GROUP_NAME")
+                .help("The name of the log group to copy from")
+                .short("g")
+                .long("group")
+                .required(true)
+                .takes_value(true)
+                .value_name("GROUP_NAME"),
+        )
+        .arg(
+            Arg::with_name("FILTER_EXPRESSION")
+                .help("The filter pattern to use. If not provided, all the events are copied.")
+                .short("f")
+                .long("filter-pattern")
+                .takes_value(true)
+                .value_name("FILTER_EXPRESSION"),
+        )
+        .arg(
+            Arg::with_name("START_TIME")
+                .help("The start of the time range")
+                .long("start-time")
+                .required(true)
+                .takes_value(true)
+                .value_name("TIME")
+                .validator(get::validate_jst_text),
+        )
+        .arg(
+            Arg::with_name("END_TIME")
+                .help("The end of the time range. Defaults to the time the copy started.")
+                .long("end-time")
+                .takes_value(true)
+                .value_name("TIME")
+                .validator(get::validate_jst_text),
+        )
+        .arg(
+            Arg::with_name("DEST_GROUP_NAME")
+                .help("The name of the log group to copy to. It and its log streams are created if missing.")
+                .long("dest-group")
+                .required(true)
+                .takes_value(true)
+                .value_name("GROUP_NAME"),
+        )
+        .arg(
+            Arg::with_name("DEST_PROFILE")
+                .help("AWS credentials profile for the destination. Defaults to '--profile'.")
+                .long("dest-profile")
+                .takes_value(true)
+                .value_name("PROFILE"),
+        )
+        .arg(
+            Arg::with_name("DEST_REGION")
+                .help("AWS region for the destination. Defaults to '--region'.")
+                .long("dest-region")
+                .takes_value(true)
+                .validator(app::validate_region)
+                .value_name("REGION"),
+        )
+        .arg(
+            Arg::with_name("DEST_ROLE_ARN")
+                .help("Role ARN to assume for the destination")
+                .long("dest-role-arn")
+                .takes_value(true)
+                .value_name("ROLE_ARN"),
+        )
+        .arg(
+            Arg::with_name("CHECKPOINT")
+                .help("Save the progress to the file and resume from it when run again with the same options. Events sent after the last saved progress may be copied twice when resuming.")
+                .long("checkpoint")
+                .takes_value(true)
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::with_name("RESTART")
+                .help("Ignore the checkpoint and copy from the beginning.")
+                .long("restart")
+                .requires("CHECKPOINT"),
+        )
+        .arg(
+            Arg::with_name("DRY_RUN")
+                .help("Only count the events to copy for each log stream.")
+                .long("dry-run"),
+        )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// StreamWriters
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// コピー元のストリーム名ごとに同じ名前のストリームへ書き込む
+///
+/// `dry_run` の場合は送信せずに件数だけを数える。
+/// ロググループは最初に一度だけ作成し、ストリームは初めて書き込むときに作成する。
+struct StreamWriters {
+    client: Option<Arc<rusoto_logs::CloudWatchLogsClient>>,
+    group_name: String,
+    writers: BTreeMap<String, LogEventsWriter>,
+    counts: BTreeMap<String, u64>,
+}
+
+impl StreamWriters {
+    fn write_events(&mut self, events: &[LogEvent]) -> Result<(), errors::Error> {
+        for event in events.iter() {
+            let stream_name = event
+                .stream_name
+                .clone()
+                .unwrap_or_else(|| "unknown".to_string());
+            *self.counts.entry(stream_name.clone()).or_insert(0) += 1;
+
+            let client = match self.client {
+                Some(ref client) => client.clone(),
+                None => continue,
+            };
+            if !self.writers.contains_key(&stream_name) {
+                let writer = LogEventsWriter::new(client, &self.group_name, &stream_name);
+                writer.create_stream_if_missing()?;
+                self.writers.insert(stream_name.clone(), writer);
+            }
+            self.writers
+                .get_mut(&stream_name)
+                .unwrap()
+                .write(InputLogEvent {
+                    message: event.message.clone(),
+                    timestamp: event.timestamp.timestamp_millis(),
+                })?;
+        }
+
+        // チェックポイントを進める前にページ全体を送信し、受け付けられたことを確認する
+        for writer in self.writers.values_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn rejected(&self) -> u64 {
+        self.writers
+            .values()
+            .map(|writer| writer.rejected().total())
+            .sum()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// run
+//
+////////////////////////////////////////////////////////////////////////////////
+
+fn prepare_checkpoint(
+    path: Option<&Path>,
+    options: &CopyOptions,
+) -> Result<Option<Checkpoint>, errors::Error> {
+    let start_time = options.start_time.timestamp_millis();
+    let end_time = options.end_time.map(|t| t.timestamp_millis());
+    let fresh = Checkpoint::new(
+        options.group_name,
+        options.filter_expression,
+        start_time,
+        end_time.unwrap_or_else(|| Utc::now().timestamp_millis()),
+    );
+    let path = match path {
+        Some(path) if !options.restart && !options.dry_run => path,
+        _ => return Ok(Some(fresh)),
+    };
+
+    match Checkpoint::load(path)? {
+        None => Ok(Some(fresh)),
+        Some(checkpoint) => {
+            if !checkpoint.is_resumable_by(
+                options.group_name,
+                options.filter_expression,
+                start_time,
+                end_time,
+            ) {
+                return Err(format_err!(
+                    "'{}' belongs to another copy. Use '--restart' or another file.",
+                    path.display()
+                ))
+                .context(errors::ErrorKind::Checkpoint)?;
+            }
+
+            if checkpoint.completed {
+                println!(
+                    "Already copied {} events to {}",
+                    checkpoint.events, options.dest_group_name
+                );
+                Ok(None)
+            } else {
+                info!("resume from {:?}", checkpoint.next_token);
+                Ok(Some(checkpoint))
+            }
+        }
+    }
+}
+
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("parse copy options");
+    let options = CopyOptions::from(matches);
+    let target = cmd::single_target(targets)?;
+
+    let dest_profile = options
+        .dest_profile
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| target.profile.clone());
+    let dest_region = match options.dest_region {
+        Some(name) => app::parse_region(name, "--dest-region")?,
+        None => target.region.clone(),
+    };
+    if dest_profile == target.profile
+        && dest_region == target.region
+        && options.dest_group_name == options.group_name
+    {
+        return Err(format_err!("Cannot copy a log group to itself"))
+            .context(errors::ErrorKind::InsufficientArguments)?;
+    }
+
+    let checkpoint_path = options.checkpoint.clone();
+    let checkpoint = match prepare_checkpoint(checkpoint_path.as_deref(), &options)? {
+        Some(checkpoint) => checkpoint,
+        None => return Ok(()),
+    };
+    // ドライランでは進捗を保存しない
+    let checkpoint_path = if options.dry_run {
+        None
+    } else {
+        checkpoint_path
+    };
+
+    info!("create writer");
+    let dest_client = if options.dry_run {
+        None
+    } else {
+        Some(Arc::new(app::cwlogs_client(
+            dest_region.clone(),
+            dest_profile.as_str(),
+            options.dest_role_arn,
+            None,
+            None,
+        )))
+    };
+    let writers = StreamWriters {
+        client: dest_client,
+        group_name: options.dest_group_name.to_string(),
+        writers: BTreeMap::new(),
+        counts: BTreeMap::new(),
+    };
+
+    info!("create reader");
+    let resume = checkpoint.resume_point(&target.client)?;
+    let stream = stream::create_log_events_stream_from(
+        Box::new(reader::FilterLogEventsReader {
+            client: target.client,
+            group_name: options.group_name.to_string(),
+            stream_names: None,
+            filter_expression: options.filter_expression.map(|s| s.to_string()),
+            request: resume.request,
+        }),
+        resume.next_token,
+    );
+
+    let dest_label = format!(
+        "{}/{}/{}",
+        dest_profile,
+        dest_region.name(),
+        options.dest_group_name
+    );
+    let dry_run = options.dry_run;
+    let progress = atty::is(atty::Stream::Stderr);
+
+    if let Some(ref client) = writers.client {
+        writer::create_group_if_missing(client, options.dest_group_name)?;
+    }
+
+    // PutLogEventsは送信が終わるまで待つので、ランタイムのスレッドを止めないようにこのスレッドで順に読む
+    //
+    // 送信が確認できたページごとにチェックポイントを保存するが、送信中に失敗した場合はそのページを再開時にもう一度送るため、
+    // 一部のイベントが重複してコピーされることがある。
+    info!("copy events");
+    let mut checkpoint = checkpoint;
+    let mut writers = writers;
+    for res in stream.wait() {
+        let mut res = res?;
+        if resume.from_timestamp {
+            res.events.retain(|event| !checkpoint.is_processed(event));
+        }
+        writers.write_events(&res.events)?;
+
+        checkpoint.advance(&res.events);
+        checkpoint.next_token = res.next_token;
+        if let Some(ref path) = checkpoint_path {
+            checkpoint.save(path)?;
+        }
+
+        if progress {
+            let verb = if dry_run { "read" } else { "copied" };
+            eprint!("\r{} events {}", checkpoint.events, verb);
+        }
+    }
+
+    checkpoint.completed = true;
+    if let Some(ref path) = checkpoint_path {
+        checkpoint.save(path)?;
+    }
+
+    if progress {
+        eprintln!();
+    }
+    if dry_run {
+        for (stream_name, count) in writers.counts.iter() {
+            println!("{}\t{}", count, stream_name);
+        }
+        println!(
+            "Would copy {} events in {} streams to {}",
+            checkpoint.events,
+            writers.counts.len(),
+            dest_label
+        );
+    } else {
+        println!(
+            "Copied {} events in {} streams to {}",
+            checkpoint.events,
+            writers.counts.len(),
+            dest_label
+        );
+        let rejected = writers.rejected();
+        if rejected > 0 {
+            println!("  {} events were rejected", rejected);
+        }
+    }
+    Ok(())
+}
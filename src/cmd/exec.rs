@@ -0,0 +1,211 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use failure::ResultExt;
+use log::{debug, info, warn};
+use rusoto_logs::InputLogEvent;
+
+use crate::cmd::put::{self, writer::LogEventsWriter};
+use crate::cmd::{self, get, Target};
+use crate::errors;
+
+/// 標準エラー出力の行に付ける目印
+const STDERR_TAG: &str = "[stderr] ";
+
+pub struct ExecOptions<'a> {
+    group_name: &'a str,
+    stream_name: &'a str,
+    create: bool,
+    flush_interval: Duration,
+    command: Vec<&'a str>,
+}
+
+impl<'a> From<&'a ArgMatches<'a>> for ExecOptions<'a> {
+    fn from(matches: &'a ArgMatches<'a>) -> Self {
+        ExecOptions {
+            group_name: matches.value_of("GROUP_NAME").unwrap(),
+            stream_name: matches.value_of("STREAM_NAME").unwrap(),
+            create: matches.is_present("CREATE"),
+            flush_interval: Duration::from_secs(
+                get::matches_count(matches, "FLUSH_INTERVAL").unwrap() as u64,
+            ),
+            command: matches.values_of("COMMAND").unwrap().collect(),
+        }
+    }
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Run a command and send its standard output and standard error to a log stream")
+        .setting(AppSettings::TrailingVarArg)
+        .arg(
+            Arg::with_name("GROUP_NAME")
+                .help("The name of the log group")
+                .short("g")
+                .long("group")
+                .required(true)
+                .takes_value(true)
+                .value_name("GROUP_NAME"),
+        )
+        .arg(
+            Arg::with_name("STREAM_NAME")
+                .help("The name of log stream")
+                .short("s")
+                .long("stream")
+                .required(true)
+                .takes_value(true)
+                .value_name("STREAM_NAME"),
+        )
+        .arg(
+            Arg::with_name("CREATE")
+                .help("Create the log group and the log stream if they do not exist.")
+                .long("create"),
+        )
+        .arg(
+            Arg::with_name("FLUSH_INTERVAL")
+                .help("Send the buffered lines at least every SECONDS.")
+                .long("flush-interval")
+                .takes_value(true)
+                .default_value("5")
+                .validator(get::validate_positive_count)
+                .value_name("SECONDS"),
+        )
+        .arg(
+            Arg::with_name("COMMAND")
+                .help("The command to run and its arguments")
+                .required(true)
+                .multiple(true)
+                .index(1)
+                .value_name("COMMAND"),
+        )
+}
+
+/// 子プロセスの出力を1行ずつ端末にそのまま流しつつ、イベントとして送信側に渡す
+///
+/// 送信に失敗して受け取り側がいなくなっても、端末への転送は子プロセスが終わるまで続ける。
+/// 端末への書き込みに失敗した場合 (`| head` の終了など) も、子プロセスが止まらないよう読み続けて送信する。
+fn pump<R, W>(
+    input: R,
+    output: W,
+    tag: &'static str,
+    sender: Sender<InputLogEvent>,
+) -> JoinHandle<io::Result<()>>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        let mut output = Some(output);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if input.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            if let Some(out) = output.as_mut() {
+                if let Err(e) = out.write_all(&line).and_then(|_| out.flush()) {
+                    warn!("stop copying the output: {}", e);
+                    output = None;
+                }
+            }
+
+            let text = format!("{}{}", tag, String::from_utf8_lossy(&line));
+            let _ = sender.send(put::line_event(text));
+        }
+        Ok(())
+    })
+}
+
+/// 子プロセスの終了コード。シグナルで終了した場合はシェルと同じく128にシグナル番号を足す。
+#[cfg(unix)]
+fn exit_code(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+#[cfg(not(unix))]
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
+}
+
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("parse exec options");
+    let options = ExecOptions::from(matches);
+    let target = cmd::single_target(targets)?;
+
+    info!("create writer");
+    let mut writer = LogEventsWriter::new(target.client, options.group_name, options.stream_name);
+    if options.create {
+        writer.create_if_missing()?;
+    }
+
+    info!("spawn {:?}", options.command);
+    let spawned = Command::new(options.command[0])
+        .args(&options.command[1..])
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(errors::ErrorKind::Io);
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            // シェルと同じく、コマンドを実行できなかった場合は127で終了する
+            crate::handle_error(errors::Error::from(e));
+            process::exit(127)
+        }
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let pumps = vec![
+        pump(
+            child.stdout.take().unwrap(),
+            io::stdout(),
+            "",
+            sender.clone(),
+        ),
+        pump(
+            child.stderr.take().unwrap(),
+            io::stderr(),
+            STDERR_TAG,
+            sender,
+        ),
+    ];
+
+    // 両方のパイプが閉じるまで送信し、最後に残りを送る
+    info!("forward events");
+    let forwarded = put::forward_events(receiver, &mut writer, options.flush_interval);
+    for pump in pumps {
+        if let Err(e) = pump.join().expect("failed to join the output thread") {
+            warn!("failed to forward the output: {}", e);
+        }
+    }
+
+    let status = child.wait().context(errors::ErrorKind::Io)?;
+    debug!("{} exited with {}", options.command[0], status);
+    debug!("put {} events", writer.written());
+    if writer.skipped() > 0 {
+        warn!("{} empty lines were skipped", writer.skipped());
+    }
+
+    let code = exit_code(status);
+    match forwarded {
+        Err(e) => {
+            // 子プロセスの終了コードを優先しつつ、送信できなかったことは必ず表示し、
+            // 子プロセスが成功していても失敗したことが呼び出し元にわかるようにする
+            crate::handle_error(e);
+            process::exit(if code == 0 { 1 } else { code })
+        }
+        Ok(()) if code != 0 => process::exit(code),
+        Ok(()) => Ok(()),
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::{format_err, ResultExt};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::prelude::*;
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::cmd::get::event::LogEvent;
+use crate::cmd::get::{self, reader, stream};
+use crate::cmd::{self, Target};
+use crate::errors;
+
+pub mod checkpoint;
+
+use self::checkpoint::Checkpoint;
+
+const CHECKPOINT_FILE_NAME: &str = "checkpoint.json";
+
+/// アーカイブの1行に書き出すイベント
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventRecord {
+    /// エポックからのミリ秒
+    pub timestamp: i64,
+    pub stream_name: Option<String>,
+    pub message: String,
+}
+
+impl From<&LogEvent> for EventRecord {
+    fn from(event: &LogEvent) -> Self {
+        EventRecord {
+            timestamp: event.timestamp.timestamp_millis(),
+            stream_name: event.stream_name.clone(),
+            message: event.message.clone(),
+        }
+    }
+}
+
+impl From<EventRecord> for LogEvent {
+    fn from(record: EventRecord) -> Self {
+        LogEvent {
+            event_id: None,
+            message: record.message,
+            timestamp: Utc.timestamp_millis(record.timestamp),
+            stream_name: record.stream_name,
+            source: None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SplitBy {
+    Stream,
+    Hour,
+}
+
+pub struct ExportOptions<'a> {
+    group_name: &'a str,
+    filter_expression: Option<&'a str>,
+    start_time: DateTime<Utc>,
+    end_time: Option<DateTime<Utc>>,
+    output_dir: &'a str,
+    split_by: SplitBy,
+    restart: bool,
+}
+
+impl<'a> From<&'a ArgMatches<'a>> for ExportOptions<'a> {
+    fn from(matches: &'a ArgMatches<'a>) -> Self {
+        ExportOptions {
+            group_name: matches.value_of("GROUP_NAME").unwrap(),
+            filter_expression: matches.value_of("FILTER_EXPRESSION"),
+            start_time: get::from_jst_text(matches.value_of("START_TIME").unwrap()),
+            end_time: matches.value_of("END_TIME").map(get::from_jst_text),
+            output_dir: matches.value_of("OUTPUT_DIR").unwrap(),
+            split_by: match matches.value_of("SPLIT_BY") {
+                Some("stream") => SplitBy::Stream,
+                _ => SplitBy::Hour,
+            },
+            restart: matches.is_present("RESTART"),
+        }
+    }
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Export the events of a log group to gzip-compressed NDJSON files")
+        .arg(
+            Arg::with_name("GROUP_NAME")
+                .help("The name of the log group")
+                .short("g")
+                .long("group")
+                .required(true)
+                .takes_value(true)
+                .value_name("GROUP_NAME"),
+        )
+        .arg(
+            Arg::with_name("FILTER_EXPRESSION")
+                .help("The filter pattern to use. If not provided, all the events are exported.")
+                .short("f")
+                .long("filter-pattern")
+                .takes_value(true)
+                .value_name("FILTER_EXPRESSION"),
+        )
+        .arg(
+            Arg::with_name("START_TIME")
+                .help("The start of the time range")
+                .long("start-time")
+                .required(true)
+                .takes_value(true)
+                .validator(get::validate_jst_text)
+                .value_name("TIME"),
+        )
+        .arg(
+            Arg::with_name("END_TIME")
+                .help("The end of the time range. Defaults to the time the export started.")
+                .long("end-time")
+                .takes_value(true)
+                .validator(get::validate_jst_text)
+                .value_name("TIME"),
+        )
+        .arg(
+            Arg::with_name("OUTPUT_DIR")
+                .help("The directory to write the archives and the checkpoint to")
+                .short("o")
+                .long("output-dir")
+                .required(true)
+                .takes_value(true)
+                .value_name("DIR"),
+        )
+        .arg(
+            Arg::with_name("SPLIT_BY")
+                .help("How to split the archive files")
+                .long("split-by")
+                .takes_value(true)
+                .possible_values(&["stream", "hour"])
+                .default_value("hour")
+                .value_name("UNIT"),
+        )
+        .arg(
+            Arg::with_name("RESTART")
+                .help("Ignore the checkpoint and export from the beginning. Existing archives in DIR are removed.")
+                .long("restart"),
+        )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// ArchiveWriter
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// ファイル名に使えない文字を置き換える
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// イベントを分割単位ごとのgzip圧縮したNDJSONファイルに追記する
+pub struct ArchiveWriter {
+    dir: PathBuf,
+    split_by: SplitBy,
+}
+
+impl ArchiveWriter {
+    pub fn new<P: AsRef<Path>>(dir: P, split_by: SplitBy) -> Self {
+        ArchiveWriter {
+            dir: dir.as_ref().to_path_buf(),
+            split_by,
+        }
+    }
+
+    fn file_name(&self, event: &LogEvent) -> String {
+        match self.split_by {
+            SplitBy::Stream => format!(
+                "{}.ndjson.gz",
+                sanitize_file_name(event.stream_name.as_ref().map_or("unknown", |s| s.as_str()))
+            ),
+            SplitBy::Hour => format!("{}.ndjson.gz", event.timestamp.format("%Y-%m-%dT%HZ")),
+        }
+    }
+
+    /// 呼び出しごとに新しいgzipメンバーとして追記し、書き込んだファイルの名前とサイズを返す
+    ///
+    /// 途中で中断された場合は、チェックポイントに保存したサイズまで切り詰めれば元の内容に戻せる。
+    pub fn write_events(&self, events: &[LogEvent]) -> io::Result<Vec<(String, u64)>> {
+        let mut files: BTreeMap<String, Vec<&LogEvent>> = BTreeMap::new();
+        for event in events.iter() {
+            files.entry(self.file_name(event)).or_default().push(event);
+        }
+
+        let mut sizes = Vec::new();
+        for (name, events) in files {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join(name.as_str()))?;
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            for event in events {
+                serde_json::to_writer(&mut encoder, &EventRecord::from(event))?;
+                encoder.write_all(b"\n")?;
+            }
+            let file = encoder.finish()?;
+            file.sync_data()?;
+            sizes.push((name, file.metadata()?.len()));
+        }
+        Ok(sizes)
+    }
+
+    /// ディレクトリにあるアーカイブのファイル名
+    fn archive_names(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.ends_with(".ndjson.gz") {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn remove_archives(&self) -> io::Result<()> {
+        for name in self.archive_names()? {
+            info!("remove {}", name);
+            fs::remove_file(self.dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// アーカイブをチェックポイントを保存した時点の内容に戻す
+    ///
+    /// 最後のチェックポイントより後に書き込んだ分は再開後にもう一度書き込まれるため、切り詰めて重複を防ぐ。
+    fn restore_archives(&self, checkpoint: &Checkpoint) -> io::Result<()> {
+        for name in self.archive_names()? {
+            let path = self.dir.join(name.as_str());
+            match checkpoint.archives.get(&name) {
+                Some(&size) => {
+                    let file = OpenOptions::new().write(true).open(&path)?;
+                    if file.metadata()?.len() > size {
+                        info!("truncate {} to {} bytes", name, size);
+                        file.set_len(size)?;
+                    }
+                }
+                None => {
+                    info!("remove {}", name);
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// run
+//
+////////////////////////////////////////////////////////////////////////////////
+
+fn prepare_checkpoint(
+    path: &Path,
+    writer: &ArchiveWriter,
+    options: &ExportOptions,
+) -> Result<Option<Checkpoint>, errors::Error> {
+    let start_time = options.start_time.timestamp_millis();
+    let end_time = options.end_time.map(|t| t.timestamp_millis());
+    let fresh = Checkpoint::new(
+        options.group_name,
+        options.filter_expression,
+        start_time,
+        end_time.unwrap_or_else(|| Utc::now().timestamp_millis()),
+    );
+    if options.restart {
+        writer.remove_archives()?;
+        return Ok(Some(fresh));
+    }
+
+    match Checkpoint::load(path)? {
+        None => {
+            if !writer.archive_names()?.is_empty() {
+                return Err(format_err!(
+                    "'{}' already contains archives. Use '--restart' or another directory.",
+                    options.output_dir
+                ))
+                .context(errors::ErrorKind::Checkpoint)?;
+            }
+            Ok(Some(fresh))
+        }
+        Some(checkpoint) => {
+            if !checkpoint.is_resumable_by(
+                options.group_name,
+                options.filter_expression,
+                start_time,
+                end_time,
+            ) {
+                return Err(format_err!(
+                    "'{}' belongs to another export. Use '--restart' or another directory.",
+                    path.display()
+                ))
+                .context(errors::ErrorKind::Checkpoint)?;
+            }
+
+            if checkpoint.completed {
+                println!(
+                    "Already exported {} events to {}",
+                    checkpoint.events, options.output_dir
+                );
+                Ok(None)
+            } else {
+                info!("resume from {:?}", checkpoint.next_token);
+                writer.restore_archives(&checkpoint)?;
+                Ok(Some(checkpoint))
+            }
+        }
+    }
+}
+
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("parse export options");
+    let options = ExportOptions::from(matches);
+    let target = cmd::single_target(targets)?;
+
+    fs::create_dir_all(options.output_dir)?;
+    let writer = ArchiveWriter::new(options.output_dir, options.split_by);
+    let checkpoint_path = Path::new(options.output_dir).join(CHECKPOINT_FILE_NAME);
+    let checkpoint = match prepare_checkpoint(&checkpoint_path, &writer, &options)? {
+        Some(checkpoint) => checkpoint,
+        None => return Ok(()),
+    };
+
+    info!("create reader");
+    let resume = checkpoint.resume_point(&target.client)?;
+    let resumed_from_timestamp = resume.from_timestamp;
+    let stream = stream::create_log_events_stream_from(
+        Box::new(reader::FilterLogEventsReader {
+            client: target.client,
+            group_name: options.group_name.to_string(),
+            stream_names: None,
+            filter_expression: options.filter_expression.map(|s| s.to_string()),
+            request: resume.request,
+        }),
+        resume.next_token,
+    );
+    let resumed = checkpoint.clone();
+    let stream = stream.map(move |mut res| {
+        if resumed_from_timestamp {
+            res.events.retain(|event| !resumed.is_processed(event));
+        }
+        res
+    });
+
+    let output_dir = options.output_dir.to_string();
+    let progress = atty::is(atty::Stream::Stderr);
+
+    info!("create futures to run");
+    let checkpoint_path_done = checkpoint_path.clone();
+    let f = stream
+        .fold(checkpoint, move |mut checkpoint, res| {
+            // アーカイブを書き終えてからチェックポイントを進める
+            // 保存する前に中断した場合は、再開時にアーカイブを保存済みのサイズまで切り詰める
+            checkpoint
+                .archives
+                .extend(writer.write_events(&res.events)?);
+
+            checkpoint.advance(&res.events);
+            checkpoint.next_token = res.next_token;
+            checkpoint.save(&checkpoint_path)?;
+
+            if progress {
+                eprint!("\r{} events exported", checkpoint.events);
+            }
+            Ok::<_, errors::Error>(checkpoint)
+        })
+        .and_then(move |mut checkpoint| {
+            checkpoint.completed = true;
+            checkpoint.save(&checkpoint_path_done)?;
+
+            if progress {
+                eprintln!();
+            }
+            println!("Exported {} events to {}", checkpoint.events, output_dir);
+            Ok(())
+        });
+
+    cmd::run_future(Box::new(f))
+}
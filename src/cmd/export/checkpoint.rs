@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use chrono::prelude::*;
+use failure::ResultExt;
+use log::{info, warn};
+use rusoto_logs::{
+    CloudWatchLogs, CloudWatchLogsClient, FilterLogEventsError, FilterLogEventsRequest,
+};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::cmd::get::event::{LogEvent, LogEventsRequest};
+use crate::errors;
+
+/// 中断した処理を再開するために保存する進捗
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub group_name: String,
+    pub filter_pattern: Option<String>,
+    pub start_time: i64,
+    pub end_time: i64,
+    /// 次に読むページのトークン
+    pub next_token: Option<String>,
+    /// 最後に処理したイベントのタイムスタンプ
+    pub last_timestamp: Option<i64>,
+    /// `last_timestamp` と同じタイムスタンプで処理済みのイベントのID
+    ///
+    /// トークンの期限が切れて `last_timestamp` から読み直すときに、処理済みのイベントを除くために使う。
+    #[serde(default)]
+    pub last_event_ids: Vec<String>,
+    /// 書き出したファイルの名前と、チェックポイントを保存した時点のサイズ
+    #[serde(default)]
+    pub archives: BTreeMap<String, u64>,
+    pub events: u64,
+    pub completed: bool,
+}
+
+/// チェックポイントから再開する位置
+pub struct ResumePoint {
+    pub request: LogEventsRequest,
+    pub next_token: Option<String>,
+    /// `last_timestamp` から読み直すかどうか
+    ///
+    /// 読み直す場合は `Checkpoint::is_processed` で処理済みのイベントを除く。
+    pub from_timestamp: bool,
+}
+
+impl Checkpoint {
+    pub fn new(
+        group_name: &str,
+        filter_pattern: Option<&str>,
+        start_time: i64,
+        end_time: i64,
+    ) -> Self {
+        Checkpoint {
+            group_name: group_name.to_string(),
+            filter_pattern: filter_pattern.map(|s| s.to_string()),
+            start_time,
+            end_time,
+            next_token: None,
+            last_timestamp: None,
+            last_event_ids: Vec::new(),
+            archives: BTreeMap::new(),
+            events: 0,
+            completed: false,
+        }
+    }
+
+    /// 同じ条件で始めた処理のチェックポイントかどうか
+    ///
+    /// 終了時刻が省略された場合は保存されている終了時刻を引き継ぐ。
+    pub fn is_resumable_by(
+        &self,
+        group_name: &str,
+        filter_pattern: Option<&str>,
+        start_time: i64,
+        end_time: Option<i64>,
+    ) -> bool {
+        self.group_name == group_name
+            && self.filter_pattern.as_deref() == filter_pattern
+            && self.start_time == start_time
+            && match end_time {
+                Some(end_time) => self.end_time == end_time,
+                None => true,
+            }
+    }
+
+    /// 処理したイベントを記録する。イベントは時系列順に渡す。
+    pub fn advance(&mut self, events: &[LogEvent]) {
+        self.events += events.len() as u64;
+
+        let last = match events.last() {
+            Some(event) => event.timestamp.timestamp_millis(),
+            None => return,
+        };
+        if self.last_timestamp != Some(last) {
+            self.last_timestamp = Some(last);
+            self.last_event_ids.clear();
+        }
+        self.last_event_ids.extend(
+            events
+                .iter()
+                .filter(|event| event.timestamp.timestamp_millis() == last)
+                .filter_map(|event| event.event_id.clone()),
+        );
+    }
+
+    /// `last_timestamp` から読み直したときに、既に処理したイベントかどうか
+    pub fn is_processed(&self, event: &LogEvent) -> bool {
+        Some(event.timestamp.timestamp_millis()) == self.last_timestamp
+            && event
+                .event_id
+                .as_ref()
+                .is_some_and(|id| self.last_event_ids.contains(id))
+    }
+
+    /// FilterLogEventsで続きを読む位置を決める
+    ///
+    /// 保存したトークンの期限が切れている場合は、最後に処理したタイムスタンプから読み直す。
+    pub fn resume_point(
+        &self,
+        client: &CloudWatchLogsClient,
+    ) -> Result<ResumePoint, errors::Error> {
+        let mut request = LogEventsRequest {
+            start_time: Some(Utc.timestamp_millis(self.start_time)),
+            end_time: Some(Utc.timestamp_millis(self.end_time)),
+            limit: None,
+        };
+        if self.next_token.is_none() || self.is_valid_token(client, &request)? {
+            return Ok(ResumePoint {
+                request,
+                next_token: self.next_token.clone(),
+                from_timestamp: false,
+            });
+        }
+
+        let last_timestamp = match self.last_timestamp {
+            Some(last_timestamp) => last_timestamp,
+            None => {
+                warn!("the saved token has expired. restart from the beginning");
+                return Ok(ResumePoint {
+                    request,
+                    next_token: None,
+                    from_timestamp: false,
+                });
+            }
+        };
+        warn!("the saved token has expired. resume from the last timestamp");
+        request.start_time = Some(Utc.timestamp_millis(last_timestamp));
+        Ok(ResumePoint {
+            request,
+            next_token: None,
+            from_timestamp: true,
+        })
+    }
+
+    /// 保存したトークンでまだ続きを読めるかどうか
+    fn is_valid_token(
+        &self,
+        client: &CloudWatchLogsClient,
+        request: &LogEventsRequest,
+    ) -> Result<bool, errors::Error> {
+        let res = client
+            .filter_log_events(FilterLogEventsRequest {
+                log_group_name: self.group_name.clone(),
+                start_time: request.start_time_value(),
+                end_time: request.end_time_value(),
+                filter_pattern: self.filter_pattern.clone(),
+                limit: Some(1),
+                next_token: self.next_token.clone(),
+                ..Default::default()
+            })
+            .sync();
+        match res {
+            Ok(_) => Ok(true),
+            Err(FilterLogEventsError::InvalidParameter(message)) => {
+                info!("invalid token: {}", message);
+                Ok(false)
+            }
+            Err(e) => Err(errors::Error::from(e)),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>, errors::Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let file = File::open(path)?;
+        let checkpoint =
+            serde_json::from_reader(BufReader::new(file)).context(errors::ErrorKind::Checkpoint)?;
+        Ok(Some(checkpoint))
+    }
+
+    /// 書き込み途中で中断しても壊れないよう一時ファイルに書いてから置き換える
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), errors::Error> {
+        let path = path.as_ref();
+        let temporary = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temporary)?);
+            serde_json::to_writer_pretty(&mut writer, self)
+                .context(errors::ErrorKind::Checkpoint)?;
+            writer.flush()?;
+        }
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(millis: i64, id: Option<&str>) -> LogEvent {
+        LogEvent {
+            event_id: id.map(|s| s.to_string()),
+            message: "message".to_string(),
+            timestamp: Utc.timestamp_millis(millis),
+            stream_name: Some("app".to_string()),
+            source: None,
+        }
+    }
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint::new("group", None, 0, 10_000)
+    }
+
+    #[test]
+    fn advance_records_the_ids_at_the_last_timestamp() {
+        let mut checkpoint = checkpoint();
+        checkpoint.advance(&[
+            event(100, Some("a")),
+            event(200, Some("b")),
+            event(200, Some("c")),
+        ]);
+
+        assert_eq!(checkpoint.events, 3);
+        assert_eq!(checkpoint.last_timestamp, Some(200));
+        assert_eq!(checkpoint.last_event_ids, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn advance_keeps_the_ids_across_pages_with_the_same_timestamp() {
+        let mut checkpoint = checkpoint();
+        checkpoint.advance(&[event(100, Some("a")), event(200, Some("b"))]);
+        checkpoint.advance(&[event(200, Some("c"))]);
+        assert_eq!(checkpoint.last_event_ids, vec!["b", "c"]);
+
+        checkpoint.advance(&[event(300, Some("d"))]);
+        assert_eq!(checkpoint.last_timestamp, Some(300));
+        assert_eq!(checkpoint.last_event_ids, vec!["d"]);
+        assert_eq!(checkpoint.events, 4);
+    }
+
+    #[test]
+    fn advance_with_no_events_changes_nothing() {
+        let mut checkpoint = checkpoint();
+        checkpoint.advance(&[event(100, Some("a"))]);
+        checkpoint.advance(&[]);
+
+        assert_eq!(checkpoint.events, 1);
+        assert_eq!(checkpoint.last_timestamp, Some(100));
+        assert_eq!(checkpoint.last_event_ids, vec!["a"]);
+    }
+
+    #[test]
+    fn is_processed_matches_only_recorded_events_at_the_last_timestamp() {
+        let mut checkpoint = checkpoint();
+        checkpoint.advance(&[event(100, Some("a")), event(200, Some("b"))]);
+
+        assert!(checkpoint.is_processed(&event(200, Some("b"))));
+        assert!(!checkpoint.is_processed(&event(200, Some("c"))));
+        // 最後のタイムスタンプより前のイベントは読み直しの範囲に含まれない
+        assert!(!checkpoint.is_processed(&event(100, Some("a"))));
+        assert!(!checkpoint.is_processed(&event(200, None)));
+    }
+
+    #[test]
+    fn is_resumable_by_the_same_conditions() {
+        let checkpoint = Checkpoint::new("group", Some("ERROR"), 0, 10_000);
+
+        assert!(checkpoint.is_resumable_by("group", Some("ERROR"), 0, Some(10_000)));
+        assert!(checkpoint.is_resumable_by("group", Some("ERROR"), 0, None));
+        assert!(!checkpoint.is_resumable_by("group", None, 0, None));
+        assert!(!checkpoint.is_resumable_by("group", Some("ERROR"), 1, None));
+        assert!(!checkpoint.is_resumable_by("other", Some("ERROR"), 0, None));
+        assert!(!checkpoint.is_resumable_by("group", Some("ERROR"), 0, Some(20_000)));
+    }
+}
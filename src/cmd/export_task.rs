@@ -0,0 +1,400 @@
+use std::thread;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use failure::{format_err, ResultExt};
+use log::{debug, info};
+use rusoto_logs::{
+    CancelExportTaskError, CancelExportTaskRequest, CloudWatchLogs, CreateExportTaskError,
+    CreateExportTaskRequest, DescribeExportTasksError, DescribeExportTasksRequest, ExportTask,
+};
+
+use crate::cmd::get::{self, printer};
+use crate::cmd::group::{group_arg, yes_arg};
+use crate::cmd::{self, Target};
+use crate::errors;
+
+/// DescribeExportTasksで絞り込める状態
+const STATUS_CODES: &[&str] = &[
+    "PENDING",
+    "PENDING_CANCEL",
+    "RUNNING",
+    "COMPLETED",
+    "CANCELLED",
+    "FAILED",
+];
+
+fn poll_interval_arg() -> Arg<'static, 'static> {
+    Arg::with_name("POLL_INTERVAL")
+        .help("The interval in seconds to check the status of the task")
+        .long("poll-interval")
+        .takes_value(true)
+        .default_value("10")
+        .validator(get::validate_positive_count)
+        .value_name("SECS")
+}
+
+fn task_id_arg() -> Arg<'static, 'static> {
+    Arg::with_name("TASK_ID")
+        .help("The ID of the export task")
+        .required(true)
+        .index(1)
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Manage the tasks exporting log groups to S3")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("create")
+                .about("Create a task exporting a log group to an S3 bucket")
+                .arg(group_arg())
+                .arg(
+                    Arg::with_name("BUCKET")
+                        .help("The name of the S3 bucket in the same region")
+                        .long("bucket")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("BUCKET"),
+                )
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("The prefix of the S3 keys. Defaults to 'exportedlogs'.")
+                        .long("prefix")
+                        .takes_value(true)
+                        .value_name("PREFIX"),
+                )
+                .arg(
+                    Arg::with_name("STREAM_PREFIX")
+                        .help("Export only the log streams whose name starts with PREFIX")
+                        .long("stream-prefix")
+                        .takes_value(true)
+                        .value_name("PREFIX"),
+                )
+                .arg(
+                    Arg::with_name("START_TIME")
+                        .help("The start of the time range")
+                        .long("start-time")
+                        .required(true)
+                        .takes_value(true)
+                        .validator(get::validate_jst_text)
+                        .value_name("TIME"),
+                )
+                .arg(
+                    Arg::with_name("END_TIME")
+                        .help("The end of the time range. Defaults to now.")
+                        .long("end-time")
+                        .takes_value(true)
+                        .validator(get::validate_jst_text)
+                        .value_name("TIME"),
+                )
+                .arg(
+                    Arg::with_name("TASK_NAME")
+                        .help("The name of the export task")
+                        .long("name")
+                        .takes_value(true)
+                        .value_name("NAME"),
+                )
+                .arg(
+                    Arg::with_name("WAIT")
+                        .help("Wait until the task finishes.")
+                        .long("wait"),
+                )
+                .arg(
+                    poll_interval_arg().help(
+                        "The interval in seconds to check the status of the task with '--wait'",
+                    ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the export tasks")
+                .arg(
+                    Arg::with_name("STATUS")
+                        .help("List only the tasks in the status")
+                        .long("status")
+                        .takes_value(true)
+                        .possible_values(STATUS_CODES)
+                        .value_name("STATUS"),
+                )
+                .arg(
+                    Arg::with_name("COLOR")
+                        .help("When to use colors in the table header.")
+                        .long("color")
+                        .takes_value(true)
+                        .possible_values(&["always", "never", "auto"])
+                        .default_value("auto")
+                        .value_name("WHEN"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("wait")
+                .about("Wait until the export tasks finish")
+                .arg(task_id_arg().multiple(true))
+                .arg(poll_interval_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("cancel")
+                .about("Cancel a pending or running export task")
+                .arg(task_id_arg())
+                .arg(yes_arg()),
+        )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Actions
+//
+////////////////////////////////////////////////////////////////////////////////
+
+fn is_transient(e: &DescribeExportTasksError) -> bool {
+    match e {
+        DescribeExportTasksError::ServiceUnavailable(_)
+        | DescribeExportTasksError::HttpDispatch(_) => true,
+        DescribeExportTasksError::Unknown(res) => cmd::is_transient_response(res),
+        _ => false,
+    }
+}
+
+/// エクスポートタスクを取得する。完了を待つ間に繰り返し呼ぶため、一時的なエラーは再試行する。
+fn describe_export_tasks(
+    target: &Target,
+    task_id: Option<&str>,
+    status_code: Option<&str>,
+) -> Result<Vec<ExportTask>, errors::Error> {
+    let mut tasks = Vec::new();
+    let mut next_token = None;
+    loop {
+        let res = cmd::retry_with_backoff(is_transient, || {
+            target
+                .client
+                .describe_export_tasks(DescribeExportTasksRequest {
+                    limit: None,
+                    next_token: next_token.clone(),
+                    status_code: status_code.map(|s| s.to_string()),
+                    task_id: task_id.map(|s| s.to_string()),
+                })
+                .sync()
+        })?;
+        tasks.extend(res.export_tasks.unwrap_or_default());
+
+        next_token = res.next_token;
+        if next_token.is_none() {
+            return Ok(tasks);
+        }
+    }
+}
+
+fn status_code(task: &ExportTask) -> &str {
+    task.status
+        .as_ref()
+        .and_then(|status| status.code.as_deref())
+        .unwrap_or("UNKNOWN")
+}
+
+/// `1h02m03s` のように経過時間を表す
+fn format_elapsed(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+/// タスクが完了するまで状態を確認し続ける
+///
+/// 端末に出力している場合は同じ行で状態と経過時間を更新し、そうでなければ状態が変わるたびに1行出力する。
+/// 失敗・取り消しで終わった場合はエラーとする。
+fn wait_for_task(target: &Target, task_id: &str, interval: Duration) -> Result<(), errors::Error> {
+    let progress = atty::is(atty::Stream::Stderr);
+    let mut last_status = String::new();
+    loop {
+        let task = describe_export_tasks(target, Some(task_id), None)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format_err!("Export task '{}' is not found", task_id))
+            .context(errors::ErrorKind::ExportTask)?;
+        let status = status_code(&task).to_string();
+        debug!("{}: {}", task_id, status);
+
+        if progress {
+            let created = task
+                .execution_info
+                .as_ref()
+                .and_then(|info| info.creation_time)
+                .unwrap_or_else(|| Utc::now().timestamp_millis());
+            let elapsed = (Utc::now().timestamp_millis() - created) / 1000;
+            // 前の表示より短くなっても残らないよう行末まで消す
+            eprint!(
+                "\r{} {} ({} elapsed)\x1b[K",
+                task_id,
+                status,
+                format_elapsed(elapsed)
+            );
+        } else if status != last_status {
+            eprintln!("{} {}", task_id, status);
+        }
+
+        match status.as_str() {
+            "COMPLETED" | "CANCELLED" | "FAILED" => {
+                if progress {
+                    eprintln!();
+                }
+                if status != "COMPLETED" {
+                    let message = task
+                        .status
+                        .and_then(|status| status.message)
+                        .unwrap_or_default();
+                    return Err(format_err!("{} {}: {}", task_id, status, message))
+                        .context(errors::ErrorKind::ExportTask)?;
+                }
+                println!("{} completed", task_id);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        last_status = status;
+        thread::sleep(interval);
+    }
+}
+
+fn poll_interval(matches: &ArgMatches) -> Duration {
+    Duration::from_secs(get::matches_count(matches, "POLL_INTERVAL").unwrap_or(10) as u64)
+}
+
+fn create(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME").unwrap();
+    let start_time = get::from_jst_text(matches.value_of("START_TIME").unwrap());
+    let end_time = matches
+        .value_of("END_TIME")
+        .map(get::from_jst_text)
+        .unwrap_or_else(Utc::now);
+    if start_time >= end_time {
+        return Err(format_err!("'--start-time' must be before '--end-time'"))
+            .context(errors::ErrorKind::InsufficientArguments)?;
+    }
+
+    let res = target
+        .client
+        .create_export_task(CreateExportTaskRequest {
+            destination: matches.value_of("BUCKET").unwrap().to_string(),
+            destination_prefix: matches.value_of("PREFIX").map(|s| s.to_string()),
+            from: start_time.timestamp_millis(),
+            log_group_name: group_name.to_string(),
+            log_stream_name_prefix: matches.value_of("STREAM_PREFIX").map(|s| s.to_string()),
+            task_name: matches.value_of("TASK_NAME").map(|s| s.to_string()),
+            to: end_time.timestamp_millis(),
+        })
+        .sync()?;
+    let task_id = res.task_id.unwrap_or_default();
+    // スクリプトから使えるよう、タスクIDだけを1行で出力する
+    println!("{}", task_id);
+
+    if matches.is_present("WAIT") {
+        wait_for_task(target, task_id.as_str(), poll_interval(matches))?;
+    }
+    Ok(())
+}
+
+fn list(targets: &[Target], matches: &ArgMatches) -> Result<(), errors::Error> {
+    let with_target = targets.len() > 1;
+    let mut headers = vec![
+        "TASK ID",
+        "NAME",
+        "STATUS",
+        "GROUP",
+        "FROM",
+        "TO",
+        "DESTINATION",
+        "CREATED",
+        "COMPLETED",
+    ];
+    if with_target {
+        headers.insert(0, "TARGET");
+    }
+
+    let format_time = |t: Option<i64>| t.map(cmd::format_epoch_millis).unwrap_or_default();
+    let mut rows = Vec::new();
+    for target in targets.iter() {
+        for task in describe_export_tasks(target, None, matches.value_of("STATUS"))? {
+            let execution_info = task.execution_info.clone().unwrap_or_default();
+            let mut row = vec![
+                task.task_id.clone().unwrap_or_default(),
+                task.task_name.clone().unwrap_or_default(),
+                status_code(&task).to_string(),
+                task.log_group_name.clone().unwrap_or_default(),
+                format_time(task.from),
+                format_time(task.to),
+                format!(
+                    "s3://{}/{}",
+                    task.destination.as_deref().unwrap_or(""),
+                    task.destination_prefix.as_deref().unwrap_or("")
+                ),
+                format_time(execution_info.creation_time),
+                format_time(execution_info.completion_time),
+            ];
+            if with_target {
+                row.insert(0, target.label());
+            }
+            rows.push(row);
+        }
+    }
+
+    let color = matches
+        .value_of("COLOR")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(printer::ColorMode::Auto);
+    cmd::print_table(&headers, &rows, color.enabled())
+}
+
+fn wait(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let interval = poll_interval(matches);
+    for task_id in matches.values_of("TASK_ID").unwrap() {
+        wait_for_task(target, task_id, interval)?;
+    }
+    Ok(())
+}
+
+fn cancel(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let task_id = matches.value_of("TASK_ID").unwrap();
+    let message = format!("Cancel the export task {} in {}?", task_id, target.label());
+    if !cmd::confirm(message.as_str(), matches.is_present("YES"))? {
+        return Ok(());
+    }
+
+    target
+        .client
+        .cancel_export_task(CancelExportTaskRequest {
+            task_id: task_id.to_string(),
+        })
+        .sync()?;
+    println!("Cancelled {} in {}", task_id, target.label());
+    Ok(())
+}
+
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("invoke export-task commands");
+    match matches.subcommand() {
+        ("create", Some(m)) => create(&cmd::single_target(targets)?, m),
+        ("list", Some(m)) => list(&targets, m),
+        ("wait", Some(m)) => wait(&cmd::single_target(targets)?, m),
+        ("cancel", Some(m)) => cancel(&cmd::single_target(targets)?, m),
+        (name, _) => Err(format_err!("Unknown export-task command: '{}'", name))
+            .context(errors::ErrorKind::NoSubCommand)?,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Errors
+//
+////////////////////////////////////////////////////////////////////////////////
+
+errors::impl_from_rusoto_error!(
+    CancelExportTaskError,
+    CreateExportTaskError,
+    DescribeExportTasksError,
+);
@@ -1,4 +1,7 @@
-use std::sync::mpsc::channel;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use chrono::prelude::*;
 use clap::{App, Arg, ArgMatches, SubCommand};
@@ -8,12 +11,19 @@ use lazy_static::lazy_static;
 use log::{debug, info};
 use rusoto_logs::CloudWatchLogsClient;
 
+use crate::cmd::{self, Target};
 use crate::errors;
 
-mod event;
-mod printer;
-mod reader;
-mod stream;
+pub mod cache;
+mod context;
+pub mod event;
+pub mod filter;
+pub mod highlight;
+mod output;
+pub mod pattern;
+pub mod printer;
+pub mod reader;
+pub mod stream;
 
 pub struct GetOptions<'a> {
     group_name: &'a str,
@@ -22,14 +32,62 @@ pub struct GetOptions<'a> {
     start_time: Option<DateTime<Utc>>, // TODO: DateTime化
     end_time: Option<DateTime<Utc>>,   // TODO: DateTime化
     watch: bool,
+    tail: Option<usize>,
+    limit: Option<usize>,
+    shards: usize,
+    concurrency: usize,
+    prefetch: usize,
     use_prefix: bool,
+    grep_patterns: Vec<&'a str>,
+    grep_v_patterns: Vec<&'a str>,
+    ignore_case: bool,
+    color: printer::ColorMode,
+    before_context: usize,
+    after_context: usize,
+    output_file: Option<&'a str>,
+    rotate_size: Option<u64>,
+    rotate_hourly: bool,
+    compress: bool,
+    tee: bool,
+    from_files: Vec<&'a str>,
+    cache_file: Option<PathBuf>,
 }
 
+/// `--watch` で新しいイベントを待つ間隔
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// `-A`/`-B`/`-C` で前後のイベントを同時に取得するグループ数
+const CONTEXT_CONCURRENCY: usize = 4;
+
 lazy_static! {
-    static ref TZ_ASIA_TOKYO: FixedOffset = FixedOffset::east(9 * 60 * 60);
+    pub static ref TZ_ASIA_TOKYO: FixedOffset = FixedOffset::east(9 * 60 * 60);
+}
+
+pub fn matches_count(matches: &ArgMatches, key: &str) -> Option<usize> {
+    matches.value_of(key).map(|s| s.parse().unwrap())
+}
+
+pub fn validate_count(s: String) -> Result<(), String> {
+    s.parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a non-negative integer", s))
 }
 
-fn from_jst_text(jst_text: &str) -> DateTime<Utc> {
+pub fn validate_positive_count(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("'{}' is not a positive integer", s)),
+    }
+}
+
+pub fn validate_jst_text(s: String) -> Result<(), String> {
+    TZ_ASIA_TOKYO
+        .datetime_from_str(s.as_str(), "%Y-%m-%d %H:%M:%S")
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a time in the form 'YYYY-MM-DD hh:mm:ss'", s))
+}
+
+pub fn from_jst_text(jst_text: &str) -> DateTime<Utc> {
     let dt = TZ_ASIA_TOKYO
         .datetime_from_str(jst_text, "%Y-%m-%d %H:%M:%S")
         .expect("failed to parse as JST time");
@@ -40,13 +98,59 @@ fn from_jst_text(jst_text: &str) -> DateTime<Utc> {
 impl<'a> From<&'a ArgMatches<'a>> for GetOptions<'a> {
     fn from(matches: &'a ArgMatches<'a>) -> Self {
         GetOptions {
-            group_name: matches.value_of("GROUP_NAME").unwrap(),
+            // --from-file の場合はロググループを指定しない
+            group_name: matches.value_of("GROUP_NAME").unwrap_or_default(),
             filter_expression: matches.value_of("FILTER_EXPRESSION"),
             start_time: matches.value_of("START_TIME").map(from_jst_text),
             end_time: matches.value_of("END_TIME").map(from_jst_text),
             stream_name: matches.value_of("STREAM_NAME"),
             watch: matches.is_present("WATCH"),
+            tail: matches_count(matches, "TAIL"),
+            limit: matches_count(matches, "LIMIT"),
+            shards: matches_count(matches, "SHARDS").unwrap_or(1),
+            concurrency: matches_count(matches, "CONCURRENCY").unwrap(),
+            prefetch: matches_count(matches, "PREFETCH").unwrap(),
             use_prefix: !matches.is_present("NO_PREFIX"),
+            grep_patterns: matches
+                .values_of("GREP")
+                .map(|values| values.collect())
+                .unwrap_or_default(),
+            grep_v_patterns: matches
+                .values_of("GREP_V")
+                .map(|values| values.collect())
+                .unwrap_or_default(),
+            ignore_case: matches.is_present("IGNORE_CASE"),
+            color: matches
+                .value_of("COLOR")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(printer::ColorMode::Auto),
+            before_context: matches_count(matches, "BEFORE_CONTEXT")
+                .or_else(|| matches_count(matches, "CONTEXT"))
+                .unwrap_or(0),
+            after_context: matches_count(matches, "AFTER_CONTEXT")
+                .or_else(|| matches_count(matches, "CONTEXT"))
+                .unwrap_or(0),
+            output_file: matches.value_of("OUTPUT_FILE"),
+            rotate_size: matches
+                .value_of("ROTATE_SIZE")
+                .map(|s| output::parse_size(s).unwrap()),
+            rotate_hourly: matches.is_present("ROTATE_HOURLY"),
+            compress: matches.is_present("COMPRESS"),
+            tee: matches.is_present("TEE"),
+            from_files: matches
+                .values_of("FROM_FILE")
+                .map(|values| values.collect())
+                .unwrap_or_default(),
+            cache_file: if matches.is_present("CACHE") {
+                Some(
+                    matches
+                        .value_of("CACHE_FILE")
+                        .map(PathBuf::from)
+                        .unwrap_or_else(cache::EventCache::default_path),
+                )
+            } else {
+                None
+            },
         }
     }
 }
@@ -59,7 +163,7 @@ pub fn sub_command(s: &'static str) -> App<'static, 'static> {
                 .help("The name of the log group")
                 .short("g")
                 .long("group")
-                .required(true)
+                .required_unless("FROM_FILE")
                 .takes_value(true)
                 .value_name("GROUP_NAME"),
         )
@@ -98,11 +202,189 @@ pub fn sub_command(s: &'static str) -> App<'static, 'static> {
                 .help("Do not stop when end of log is reached.")
                 .short("w")
                 .long("watch"),
-        ).arg(
+        )
+        .arg(
+            Arg::with_name("LIMIT")
+                .help("Stop after printing NUM events.")
+                .short("n")
+                .long("limit")
+                .takes_value(true)
+                .validator(validate_positive_count)
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("SHARDS")
+                .help("Split the time range into NUM shards and scan them in parallel. Requires '--filter-pattern' and '--start-time'.")
+                .long("shards")
+                .takes_value(true)
+                .validator(validate_positive_count)
+                .requires_all(&["FILTER_EXPRESSION", "START_TIME"])
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("CONCURRENCY")
+                .help("The maximum number of shards scanned at the same time.")
+                .long("concurrency")
+                .takes_value(true)
+                .validator(validate_positive_count)
+                .default_value("4")
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("PREFETCH")
+                .help("The number of pages fetched ahead while printing. 0 disables prefetching.")
+                .long("prefetch")
+                .takes_value(true)
+                .validator(validate_count)
+                .default_value("1")
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("TAIL")
+                .help("Print only the last NUM events of the stream. Combine with '--watch' to keep following it.")
+                .long("tail")
+                .takes_value(true)
+                .validator(validate_count)
+                .value_name("NUM"),
+        )
+        .arg(
         Arg::with_name("NO_PREFIX")
             .help("Do not display the time and stream name in the event at the begin of the line.")
             .long("no-prefix"),
     )
+        .arg(
+            Arg::with_name("GREP")
+                .help("Print only the events whose message matches the regular expression. Applied after '--filter-pattern'.")
+                .long("grep")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("REGEX"),
+        )
+        .arg(
+            Arg::with_name("GREP_V")
+                .help("Do not print the events whose message matches the regular expression.")
+                .long("grep-v")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("REGEX"),
+        )
+        .arg(
+            Arg::with_name("IGNORE_CASE")
+                .help("Ignore case distinctions in '--grep' and '--grep-v'.")
+                .short("i")
+                .long("ignore-case"),
+        )
+        .arg(
+            Arg::with_name("COLOR")
+                .help("When to use colors. Matched terms of '--filter-pattern' and '--grep' are highlighted.")
+                .long("color")
+                .takes_value(true)
+                .possible_values(&["always", "never", "auto"])
+                .default_value("auto")
+                .value_name("WHEN"),
+        )
+        .arg(
+            Arg::with_name("AFTER_CONTEXT")
+                .help("Print NUM events from the same stream following each matched event.")
+                .short("A")
+                .long("after-context")
+                .takes_value(true)
+                .validator(validate_count)
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("BEFORE_CONTEXT")
+                .help("Print NUM events from the same stream preceding each matched event.")
+                .short("B")
+                .long("before-context")
+                .takes_value(true)
+                .validator(validate_count)
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("CONTEXT")
+                .help("Print NUM events of context before and after each matched event.")
+                .short("C")
+                .long("context")
+                .takes_value(true)
+                .validator(validate_count)
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("FROM_FILE")
+                .help("Read the events from archives written by 'export' or exported to S3 instead of CloudWatch Logs. Directories are searched recursively. '--filter-pattern' is evaluated locally, and patterns using syntax not supported locally are rejected.")
+                .long("from-file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&[
+                    "GROUP_NAME",
+                    "STREAM_NAME",
+                    "WATCH",
+                    "TAIL",
+                    "SHARDS",
+                    "AFTER_CONTEXT",
+                    "BEFORE_CONTEXT",
+                    "CONTEXT",
+                ])
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::with_name("OUTPUT_FILE")
+                .help("Write the output to the file instead of the standard output.")
+                .short("o")
+                .long("output-file")
+                .takes_value(true)
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::with_name("ROTATE_SIZE")
+                .help("Rotate the output file when it grows beyond SIZE (e.g. 100M).")
+                .long("rotate-size")
+                .takes_value(true)
+                .requires("OUTPUT_FILE")
+                .validator(|s| {
+                    output::parse_size(s.as_str())
+                        .map(|_| ())
+                        .ok_or_else(|| format!("'{}' is not a valid size", s))
+                })
+                .value_name("SIZE"),
+        )
+        .arg(
+            Arg::with_name("ROTATE_HOURLY")
+                .help("Rotate the output file every hour.")
+                .long("rotate-hourly")
+                .requires("OUTPUT_FILE"),
+        )
+        .arg(
+            Arg::with_name("COMPRESS")
+                .help("Compress rotated output files with gzip.")
+                .long("compress")
+                .requires("OUTPUT_FILE"),
+        )
+        .arg(
+            Arg::with_name("TEE")
+                .help("Write to the standard output as well as '--output-file'.")
+                .long("tee")
+                .requires("OUTPUT_FILE"),
+        )
+        .arg(
+            Arg::with_name("CACHE")
+                .help("Store the fetched events in a local cache and fetch only the time ranges not cached yet. When the unfiltered events of the range are cached and '--filter-pattern' can be evaluated locally, it is evaluated on them.")
+                .long("cache")
+                .requires("START_TIME")
+                .conflicts_with_all(&["WATCH", "TAIL", "FROM_FILE"]),
+        )
+        .arg(
+            Arg::with_name("CACHE_FILE")
+                .help("The SQLite database to use as the cache. Defaults to '$XDG_CACHE_HOME/razy-awslogs/events.sqlite3'.")
+                .long("cache-file")
+                .takes_value(true)
+                .requires("CACHE")
+                .value_name("PATH"),
+        )
 }
 
 trait Runner {
@@ -129,8 +411,10 @@ impl Runner for OneShotRunner {
     ) -> Box<Future<Item = (), Error = errors::Error> + Send> {
         info!("iterate log events stream");
 
+        let mut printer = printer;
         let fut = log_events.for_each(move |res| {
-            printer.print_events(&res.events);
+            printer.print_events(&res.events)?;
+            printer.flush()?;
             Ok(())
         });
 
@@ -138,27 +422,135 @@ impl Runner for OneShotRunner {
     }
 }
 
-#[derive(Debug)]
-enum Payload {
-    Done,
-    Failure(errors::Error),
+/// マッチしたイベントごとに同じストリームの前後のイベントを取得して表示する
+#[derive(Clone)]
+struct ContextRunner {
+    /// 取得元のラベルとクライアントの組 (ターゲットが1つの場合のラベルは `None`)
+    clients: Vec<(Option<String>, Arc<CloudWatchLogsClient>)>,
+    group_name: String,
+    before: usize,
+    after: usize,
+}
+
+impl ContextRunner {
+    fn client_for(&self, event: &event::LogEvent) -> Arc<CloudWatchLogsClient> {
+        self.clients
+            .iter()
+            .find(|(label, _)| label == &event.source)
+            .unwrap_or(&self.clients[0])
+            .1
+            .clone()
+    }
+}
+
+impl Runner for ContextRunner {
+    fn run(
+        &self,
+        log_events: Box<stream::LogEventResponseStream>,
+        printer: Box<dyn printer::Printer>,
+    ) -> Box<dyn Future<Item = (), Error = errors::Error> + Send> {
+        info!("iterate log events stream with context");
+
+        let runner = self.clone();
+
+        // イベントごとに前後2回のGetLogEventsを呼ぶので、同時に取得するグループ数を絞る
+        let groups = log_events
+            .map(|res| futures::stream::iter_ok(res.events))
+            .flatten()
+            .map(move |event| {
+                context::fetch_context(
+                    runner.client_for(&event),
+                    runner.group_name.as_str(),
+                    event,
+                    runner.before,
+                    runner.after,
+                )
+            })
+            .buffered(CONTEXT_CONCURRENCY);
+
+        // 次のグループと重なり得るのは直前に表示した1グループ分のイベントだけなので、それ以上は保持しない
+        let window = self.before + self.after + 1;
+        let mut printer = printer;
+        let mut previous: Vec<event::LogEvent> = Vec::new();
+        let fut = groups.for_each(move |group| {
+            let (overlapped, events) = context::trim_overlap(&previous, group.clone());
+            if !overlapped && !previous.is_empty() {
+                printer.print_separator()?;
+            }
+            printer.print_events(&events)?;
+            printer.flush()?;
+
+            if overlapped {
+                previous.extend(events);
+                if previous.len() > window {
+                    previous.drain(..previous.len() - window);
+                }
+            } else {
+                previous = group;
+            }
+            Ok(())
+        });
+
+        Box::new(fut)
+    }
 }
 
 fn create_log_events_stream(
-    client: CloudWatchLogsClient,
+    client: Arc<CloudWatchLogsClient>,
     options: &GetOptions,
 ) -> Result<Box<stream::LogEventResponseStream>, errors::Error> {
+    // ローカルで絞り込む場合は1ページの件数を絞ると呼び出し回数が増えるだけなので指定しない
+    let local_filtered = !options.grep_patterns.is_empty() || !options.grep_v_patterns.is_empty();
+    let limit = match options.limit {
+        Some(limit) if limit > 0 && !local_filtered => Some(limit.min(reader::MAX_LIMIT) as i64),
+        _ => None,
+    };
     let request = event::LogEventsRequest {
         start_time: options.start_time,
         end_time: options.end_time,
+        limit,
     };
     Ok(match options.filter_expression {
         Some(filter) => {
+            if options.watch || options.tail.is_some() {
+                return Err(format_err!(
+                    "'--watch' and '--tail' cannot be used with '--filter-pattern'"
+                ))
+                .context(errors::ErrorKind::InsufficientArguments)?;
+            }
+
+            if options.shards > 1 {
+                // 期間を分割して並行に読む
+                let start_time = options
+                    .start_time
+                    .ok_or_else(|| format_err!("Need to specify '--start-time' with '--shards'"))
+                    .context(errors::ErrorKind::InsufficientArguments)?;
+                let end_time = options.end_time.unwrap_or_else(Utc::now);
+
+                let readers = request
+                    .split(start_time, end_time, options.shards)
+                    .into_iter()
+                    .map(|request| {
+                        Box::new(reader::FilterLogEventsReader {
+                            client: client.clone(),
+                            group_name: options.group_name.to_string(),
+                            stream_names: None,
+                            filter_expression: Some(filter.to_string()),
+                            request,
+                        }) as Box<dyn reader::LogEventsReader + Send>
+                    })
+                    .collect();
+                return Ok(stream::create_sharded_log_events_stream(
+                    readers,
+                    options.concurrency,
+                ));
+            }
+
             stream::create_log_events_stream(Box::new(reader::FilterLogEventsReader {
                 client,
                 group_name: options.group_name.to_string(),
                 stream_names: None, // NOTE: ストリームの指定どうするか確認する
-                filter_expression: filter.to_string(),
+                filter_expression: Some(filter.to_string()),
                 request,
             }))
         }
@@ -171,66 +563,406 @@ fn create_log_events_stream(
                 .context(errors::ErrorKind::InsufficientArguments),
             )?;
 
-            stream::create_log_events_stream(Box::new(reader::GetLogEventsReader {
-                client,
-                group_name: options.group_name.to_string(),
-                stream_name: stream_name.to_string(),
-                request,
-            }))
+            let group_name = options.group_name.to_string();
+            let stream_name = stream_name.to_string();
+            let reader = move |start_from_head: Option<bool>, request: event::LogEventsRequest| {
+                Box::new(reader::GetLogEventsReader {
+                    client: client.clone(),
+                    group_name: group_name.clone(),
+                    stream_name: stream_name.clone(),
+                    start_from_head,
+                    request,
+                }) as Box<dyn reader::LogEventsReader + Send>
+            };
+            create_get_log_events_stream(reader, request, options.tail, options.watch)
+        }
+    })
+}
+
+/// GetLogEventsで1つのストリームを読むストリームを作る
+///
+/// `reader` は読む向き (`start_from_head`) と期間を受け取ってリーダーを作る。
+/// 向きを指定しないとAPIは末尾から返すので、先頭から読む場合は必ず `Some(true)` を渡す。
+fn create_get_log_events_stream<F>(
+    reader: F,
+    request: event::LogEventsRequest,
+    tail: Option<usize>,
+    watch: bool,
+) -> Box<stream::LogEventResponseStream>
+where
+    F: Fn(Option<bool>, event::LogEventsRequest) -> Box<dyn reader::LogEventsReader + Send>
+        + Send
+        + 'static,
+{
+    match tail {
+        Some(count) => {
+            // 末尾から遡って最後のN件を集め、必要ならその続きから監視する
+            let tail_request = event::LogEventsRequest {
+                limit: Some(count.clamp(1, reader::MAX_LIMIT) as i64),
+                ..request
+            };
+            let tail = stream::collect_tail(reader(Some(false), tail_request), count);
+            Box::new(
+                tail.map(move |events| {
+                    let start_time = events
+                        .last()
+                        .map(|event| event.timestamp + chrono::Duration::milliseconds(1))
+                        .or(request.start_time);
+                    let head = futures::stream::once(Ok(event::LogEventsResponse {
+                        events,
+                        next_token: None,
+                    }));
+
+                    if watch {
+                        let request = event::LogEventsRequest {
+                            start_time,
+                            end_time: None,
+                            ..request
+                        };
+                        Box::new(head.chain(stream::create_watch_stream(
+                            reader(Some(true), request),
+                            WATCH_INTERVAL,
+                        ))) as Box<stream::LogEventResponseStream>
+                    } else {
+                        Box::new(head) as Box<stream::LogEventResponseStream>
+                    }
+                })
+                .flatten_stream(),
+            )
         }
+        None if watch => stream::create_watch_stream(reader(Some(true), request), WATCH_INTERVAL),
+        // --limit で先頭からN件で止められるよう、古い順に読む
+        None => stream::create_log_events_stream(reader(Some(true), request)),
+    }
+}
+
+fn create_sink(options: &GetOptions) -> Result<Box<dyn Write + Send>, errors::Error> {
+    let path = match options.output_file {
+        Some(path) => path,
+        None => return Ok(Box::new(printer::stdout_sink())),
+    };
+
+    let file = output::RotatingFile::open(
+        path,
+        options.rotate_size,
+        options.rotate_hourly,
+        options.compress,
+    )?;
+    Ok(if options.tee {
+        Box::new(output::Tee::new(file, printer::stdout_sink()))
+    } else {
+        Box::new(file)
+    })
+}
+
+fn create_printer(options: &GetOptions) -> Result<Box<dyn printer::Printer>, errors::Error> {
+    // ファイルに書き出す場合は端末かどうかで色付けを判断しない
+    let enable_color = match (options.output_file, options.color) {
+        (Some(_), printer::ColorMode::Auto) => false,
+        (_, color) => color.enabled(),
+    };
+    let sink = create_sink(options)?;
+    let highlighter = highlight::Highlighter::new(
+        options.filter_expression,
+        &options.grep_patterns,
+        options.ignore_case,
+    )?;
+
+    Ok(if options.use_prefix {
+        Box::new(printer::LogPrinter::new(sink, enable_color, highlighter))
+            as Box<dyn printer::Printer>
+    } else {
+        let highlighter = if enable_color {
+            Some(highlighter)
+        } else {
+            None
+        };
+        Box::new(printer::MessagePrinter::new(sink, highlighter)) as Box<dyn printer::Printer>
+    })
+}
+
+fn create_file_log_events_stream(
+    options: &GetOptions,
+) -> Result<Box<stream::LogEventResponseStream>, errors::Error> {
+    let request = event::LogEventsRequest {
+        start_time: options.start_time,
+        end_time: options.end_time,
+        limit: None,
+    };
+
+    let mut streams = Vec::new();
+    for path in options.from_files.iter() {
+        for file in reader::collect_archive_files(path)? {
+            debug!("read {}", file.display());
+            streams.push(stream::create_file_log_events_stream(file, request));
+        }
+    }
+
+    let stream = stream::merge_log_events_streams(streams);
+    Ok(match options.filter_expression {
+        Some(filter) => {
+            let pattern = pattern::FilterPattern::parse(filter)?;
+            Box::new(stream.map(move |res| pattern.apply(res)))
+                as Box<stream::LogEventResponseStream>
+        }
+        None => stream,
+    })
+}
+
+fn create_cached_log_events_stream(
+    target: &Target,
+    cache: Arc<cache::EventCache>,
+    options: &GetOptions,
+) -> Result<Box<stream::LogEventResponseStream>, errors::Error> {
+    let start_time = options
+        .start_time
+        .ok_or_else(|| format_err!("Need to specify '--start-time' with '--cache'"))
+        .context(errors::ErrorKind::InsufficientArguments)?;
+    let end_time = options.end_time.unwrap_or_else(Utc::now);
+
+    let client = target.client.clone();
+    let group_name = options.group_name.to_string();
+    let stream_name = options.stream_name.map(|s| s.to_string());
+    let raw_key = cache::CacheKey {
+        profile: target.profile.clone(),
+        region: target.region.name().to_string(),
+        group_name: group_name.clone(),
+        stream_name: stream_name.clone(),
+        filter_pattern: None,
+    };
+
+    // 絞り込まずに取得したイベントが期間全体にわたってキャッシュ済みなら、ローカルで絞り込む
+    // ローカルで評価できないパターンは結果が変わらないようAPIに任せる
+    let local_pattern = match options.filter_expression {
+        Some(filter) => match pattern::FilterPattern::parse(filter) {
+            Ok(pattern) => {
+                let raw_covered = cache
+                    .missing_ranges(
+                        &raw_key,
+                        start_time.timestamp_millis(),
+                        end_time.timestamp_millis(),
+                    )?
+                    .is_empty();
+                if raw_covered {
+                    debug!("apply the filter pattern to the cached events");
+                    Some(pattern)
+                } else {
+                    None
+                }
+            }
+            Err(e) => {
+                debug!("the filter pattern is not evaluated locally: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let filter_expression = match local_pattern {
+        Some(_) => None,
+        None => options.filter_expression.map(|s| s.to_string()),
+    };
+    let key = cache::CacheKey {
+        filter_pattern: filter_expression.clone(),
+        ..raw_key
+    };
+
+    // キャッシュはイベントIDで重複を除くので、ストリームを指定した場合もイベントIDを返すFilterLogEventsで取得する
+    let reader_factory = move |request: event::LogEventsRequest| {
+        Box::new(reader::FilterLogEventsReader {
+            client: client.clone(),
+            group_name: group_name.clone(),
+            stream_names: stream_name.clone().map(|s| vec![s]),
+            filter_expression: filter_expression.clone(),
+            request,
+        }) as Box<dyn reader::LogEventsReader + Send>
+    };
+
+    let stream = stream::create_log_events_stream(Box::new(cache::CachedLogEventsReader {
+        cache,
+        key,
+        start_time,
+        end_time,
+        reader_factory: Arc::new(reader_factory),
+    }));
+    Ok(match local_pattern {
+        Some(pattern) => Box::new(stream.map(move |res| pattern.apply(res)))
+            as Box<stream::LogEventResponseStream>,
+        None => stream,
     })
 }
 
-fn create_printer(options: &GetOptions) -> Box<printer::Printer> {
-    if options.use_prefix {
-        Box::new(printer::LogPrinter::default()) as Box<printer::Printer>
+fn create_target_log_events_stream(
+    target: &Target,
+    cache: &Option<Arc<cache::EventCache>>,
+    options: &GetOptions,
+) -> Result<Box<stream::LogEventResponseStream>, errors::Error> {
+    match cache {
+        Some(cache) => create_cached_log_events_stream(target, cache.clone(), options),
+        None => create_log_events_stream(target.client.clone(), options),
+    }
+}
+
+fn create_merged_log_events_stream(
+    targets: Vec<Target>,
+    options: &GetOptions,
+) -> Result<Box<stream::LogEventResponseStream>, errors::Error> {
+    let cache = match options.cache_file {
+        Some(ref path) => {
+            debug!("open cache {}", path.display());
+            Some(Arc::new(cache::EventCache::open(path)?))
+        }
+        None => None,
+    };
+
+    if targets.len() == 1 {
+        return create_target_log_events_stream(&targets[0], &cache, options);
+    }
+
+    // 複数のターゲットを指定された場合は取得元のラベルを付けてタイムスタンプ順にまとめる
+    let mut streams = Vec::new();
+    for target in targets {
+        let label = target.label();
+        let stream = create_target_log_events_stream(&target, &cache, options)?
+            .map(move |res| res.with_source(label.as_str()));
+        streams.push(Box::new(stream) as Box<stream::LogEventResponseStream>);
+    }
+
+    if options.watch {
+        Ok(stream::merge_watch_log_events_streams(
+            streams,
+            WATCH_INTERVAL,
+        ))
     } else {
-        Box::new(printer::MessagePrinter::default()) as Box<printer::Printer>
+        Ok(stream::merge_log_events_streams(streams))
     }
 }
 
-pub fn run(client: CloudWatchLogsClient, matches: &ArgMatches) -> Result<(), errors::Error> {
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
     info!("parse get options");
     let options = GetOptions::from(matches);
 
     // ログの読み取り方法を決める (get-log-events or filter-log-events)
     info!("create reader");
-    let stream = create_log_events_stream(client, &options)?;
+    let clients = targets
+        .iter()
+        .map(|target| {
+            let label = if targets.len() > 1 {
+                Some(target.label())
+            } else {
+                None
+            };
+            (label, target.client.clone())
+        })
+        .collect::<Vec<_>>();
+    let stream = if options.from_files.is_empty() {
+        create_merged_log_events_stream(targets, &options)?
+    } else {
+        create_file_log_events_stream(&options)?
+    };
+
+    // --limit の場合は余分なページを取得しないよう先読みしない
+    let stream = if options.prefetch > 0 && options.limit.is_none() {
+        stream::prefetch_log_events_stream(stream, options.prefetch)
+    } else {
+        stream
+    };
+
+    // 取得後にローカルで絞り込む
+    info!("create local filter");
+    let local_filter = filter::LocalFilter::new(
+        &options.grep_patterns,
+        &options.grep_v_patterns,
+        options.ignore_case,
+    )?;
+    let stream = if local_filter.is_empty() {
+        stream
+    } else {
+        Box::new(stream.map(move |res| local_filter.apply(res)))
+            as Box<stream::LogEventResponseStream>
+    };
+    let stream = match options.limit {
+        Some(limit) => stream::limit_log_events_stream(stream, limit),
+        None => stream,
+    };
 
     // ログの表示方法を決める
     info!("create printer");
-    let printer = create_printer(&options);
+    let printer = create_printer(&options)?;
 
     // 実行方法を決める
-    // TODO: Watch用のランナーを作る
     info!("create runner");
-    let runner = OneShotRunner::default();
-
-    let (sender, receiver) = channel();
-    let sender_ok = sender.clone();
-    let sender_err = sender.clone();
+    let runner = if options.before_context > 0 || options.after_context > 0 {
+        Box::new(ContextRunner {
+            clients,
+            group_name: options.group_name.to_string(),
+            before: options.before_context,
+            after: options.after_context,
+        }) as Box<dyn Runner>
+    } else {
+        Box::new(OneShotRunner::default()) as Box<dyn Runner>
+    };
 
     info!("create futures to run");
-    let f = runner
-        .run(stream, printer)
-        .map(move |_| {
-            sender_ok.send(Payload::Done).unwrap();
-            ()
-        })
-        .map_err(move |e| {
-            sender_err.send(Payload::Failure(e)).unwrap();
-            ()
-        });
+    match cmd::run_future(runner.run(stream, printer)) {
+        Err(ref e) if e.kind() == errors::ErrorKind::BrokenPipe => {
+            debug!("output closed");
+            Ok(())
+        }
+        result => result,
+    }
+}
 
-    info!("run!!");
-    tokio::run(f);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::get::event::LogEvent;
+    use crate::cmd::get::reader::tests::PagedReader;
 
-    info!("receive result");
-    match receiver.recv().context(errors::ErrorKind::SyncChannel)? {
-        Payload::Done => Ok(()),
-        Payload::Failure(e) => {
-            debug!("error occurred: {}", e);
-            Err(e)
+    fn event(second: u32) -> LogEvent {
+        LogEvent {
+            event_id: None,
+            message: second.to_string(),
+            timestamp: Utc.ymd(2019, 1, 2).and_hms(3, 4, second),
+            stream_name: Some("app".to_string()),
+            source: None,
         }
     }
+
+    /// GetLogEventsと同じく、`start_from_head` が `Some(true)` でなければ新しいページから返す
+    fn reader(
+        start_from_head: Option<bool>,
+        _request: event::LogEventsRequest,
+    ) -> Box<dyn reader::LogEventsReader + Send> {
+        let mut pages = vec![
+            vec![event(0), event(1)],
+            vec![event(2), event(3)],
+            vec![event(4), event(5)],
+        ];
+        if start_from_head != Some(true) {
+            pages.reverse();
+        }
+        Box::new(PagedReader::new(pages))
+    }
+
+    #[test]
+    fn limit_prints_the_first_events_of_the_stream() {
+        let request = event::LogEventsRequest {
+            start_time: None,
+            end_time: None,
+            limit: Some(3),
+        };
+        let stream = stream::limit_log_events_stream(
+            create_get_log_events_stream(reader, request, None, false),
+            3,
+        );
+
+        let messages: Vec<String> = stream
+            .map(|res| futures::stream::iter_ok::<_, errors::Error>(res.events))
+            .flatten()
+            .map(|event| event.message)
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(messages, vec!["0", "1", "2"]);
+    }
 }
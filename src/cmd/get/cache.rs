@@ -0,0 +1,589 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::prelude::*;
+use chrono::Duration;
+use failure::{format_err, Fail, ResultExt};
+use futures::future;
+use futures::prelude::*;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags};
+
+use super::event::{LogEvent, LogEventsRequest, LogEventsResponse};
+use super::reader::{LogEventResponseFuture, LogEventsReader, MAX_LIMIT};
+use super::stream;
+use crate::errors;
+
+/// 取り込みが遅れて届くイベントを取りこぼさないよう、直近のこの期間は取得済みとして扱わない
+const INGESTION_DELAY_MINUTES: i64 = 5;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY,
+    profile TEXT NOT NULL,
+    region TEXT NOT NULL,
+    group_name TEXT NOT NULL,
+    filter_pattern TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    stream_name TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    UNIQUE (profile, region, group_name, filter_pattern, event_id)
+);
+CREATE INDEX IF NOT EXISTS events_time
+    ON events (profile, region, group_name, filter_pattern, timestamp);
+CREATE TABLE IF NOT EXISTS coverage (
+    profile TEXT NOT NULL,
+    region TEXT NOT NULL,
+    group_name TEXT NOT NULL,
+    filter_pattern TEXT NOT NULL,
+    stream_key TEXT NOT NULL,
+    start_time INTEGER NOT NULL,
+    end_time INTEGER NOT NULL
+);
+CREATE VIRTUAL TABLE IF NOT EXISTS events_fts
+    USING fts5 (message, content = 'events', content_rowid = 'id');
+CREATE TRIGGER IF NOT EXISTS events_fts_insert AFTER INSERT ON events BEGIN
+    INSERT INTO events_fts (rowid, message) VALUES (new.id, new.message);
+END;
+";
+
+/// キャッシュの区分
+///
+/// フィルタパターンで絞り込んだ結果はパターンごとに別のものとして保存する。
+#[derive(Debug, Clone)]
+pub struct CacheKey {
+    /// AWSのプロファイル名
+    pub profile: String,
+    pub region: String,
+    pub group_name: String,
+    /// `None` はロググループ内の全ストリーム
+    pub stream_name: Option<String>,
+    pub filter_pattern: Option<String>,
+}
+
+impl CacheKey {
+    fn stream_key(&self) -> &str {
+        self.stream_name.as_deref().unwrap_or("")
+    }
+
+    fn filter_key(&self) -> &str {
+        self.filter_pattern.as_deref().unwrap_or("")
+    }
+}
+
+/// `[start, end]` のうち `covered` に含まれない期間を返す (いずれも両端を含むミリ秒)
+fn missing_ranges(start: i64, end: i64, mut covered: Vec<(i64, i64)>) -> Vec<(i64, i64)> {
+    covered.sort();
+
+    let mut missing = Vec::new();
+    let mut cursor = start;
+    for (covered_start, covered_end) in covered {
+        if covered_end < cursor {
+            continue;
+        }
+        if covered_start > end {
+            break;
+        }
+        if covered_start > cursor {
+            missing.push((cursor, covered_start - 1));
+        }
+        cursor = cursor.max(covered_end + 1);
+    }
+    if cursor <= end {
+        missing.push((cursor, end));
+    }
+    missing
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// EventCache
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// 取得したイベントを保存するSQLiteデータベース
+pub struct EventCache {
+    conn: Mutex<Connection>,
+}
+
+impl EventCache {
+    /// `$XDG_CACHE_HOME/razy-awslogs/events.sqlite3` (未設定なら `~/.cache` の下)
+    pub fn default_path() -> PathBuf {
+        let base = env::var("XDG_CACHE_HOME")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                PathBuf::from(env::var("HOME").unwrap_or_else(|_| ".".to_string())).join(".cache")
+            });
+        base.join("razy-awslogs").join("events.sqlite3")
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, errors::Error> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let conn = Connection::open(path).context(errors::ErrorKind::Cache)?;
+        conn.execute_batch(SCHEMA)
+            .context(errors::ErrorKind::Cache)?;
+
+        Ok(EventCache {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// 既存のキャッシュを読み取り専用で開く。ファイルがなければエラーとする。
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, errors::Error> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Err(format_err!(
+                "Cache file '{}' does not exist",
+                path.display()
+            ))
+            .context(errors::ErrorKind::Cache)?;
+        }
+
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .context(errors::ErrorKind::Cache)?;
+
+        Ok(EventCache {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn missing_ranges(
+        &self,
+        key: &CacheKey,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<(i64, i64)>, errors::Error> {
+        let conn = self.conn.lock().unwrap();
+        // ストリームを指定した取得には全ストリーム分の取得結果も使える
+        let mut statement = conn
+            .prepare(
+                "SELECT start_time, end_time FROM coverage
+                 WHERE profile = ?1 AND region = ?2 AND group_name = ?3 AND filter_pattern = ?4
+                   AND (stream_key = ?5 OR stream_key = '')
+                   AND end_time >= ?6 AND start_time <= ?7",
+            )
+            .context(errors::ErrorKind::Cache)?;
+        let covered = statement
+            .query_map(
+                params![
+                    key.profile,
+                    key.region,
+                    key.group_name,
+                    key.filter_key(),
+                    key.stream_key(),
+                    start,
+                    end
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .context(errors::ErrorKind::Cache)?
+            .collect::<Result<Vec<(i64, i64)>, _>>()
+            .context(errors::ErrorKind::Cache)?;
+
+        Ok(missing_ranges(start, end, covered))
+    }
+
+    /// イベントを保存する。同じイベントIDのイベントは1件にまとめる。
+    pub fn store_events(&self, key: &CacheKey, events: &[LogEvent]) -> Result<(), errors::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().context(errors::ErrorKind::Cache)?;
+        {
+            let mut insert = tx
+                .prepare(
+                    "INSERT OR IGNORE INTO events
+                     (profile, region, group_name, filter_pattern, event_id, stream_name, timestamp, message)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                )
+                .context(errors::ErrorKind::Cache)?;
+            for event in events.iter() {
+                let event_id = event
+                    .event_id
+                    .as_ref()
+                    .ok_or_else(|| format_err!("The event has no event ID"))
+                    .context(errors::ErrorKind::Cache)?;
+                insert
+                    .execute(params![
+                        key.profile,
+                        key.region,
+                        key.group_name,
+                        key.filter_key(),
+                        event_id,
+                        event.stream_name.as_deref().unwrap_or(""),
+                        event.timestamp.timestamp_millis(),
+                        event.message
+                    ])
+                    .context(errors::ErrorKind::Cache)?;
+            }
+        }
+        tx.commit().context(errors::ErrorKind::Cache)?;
+        Ok(())
+    }
+
+    /// 期間 `[start, end]` を取得済みとして記録する
+    ///
+    /// 期間内のイベントをすべて `store_events` で保存してから呼ぶ。
+    pub fn mark_covered(&self, key: &CacheKey, start: i64, end: i64) -> Result<(), errors::Error> {
+        let covered_end =
+            end.min((Utc::now() - Duration::minutes(INGESTION_DELAY_MINUTES)).timestamp_millis());
+        if start > covered_end {
+            return Ok(());
+        }
+
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO coverage
+             (profile, region, group_name, filter_pattern, stream_key, start_time, end_time)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                key.profile,
+                key.region,
+                key.group_name,
+                key.filter_key(),
+                key.stream_key(),
+                start,
+                covered_end
+            ],
+        )
+        .context(errors::ErrorKind::Cache)?;
+        Ok(())
+    }
+
+    /// 期間内のイベントを時系列順に `offset` 件目から最大 `limit` 件読む
+    pub fn load(
+        &self,
+        key: &CacheKey,
+        start: i64,
+        end: i64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<LogEvent>, errors::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT stream_name, timestamp, message FROM events
+                 WHERE profile = ?1 AND region = ?2 AND group_name = ?3 AND filter_pattern = ?4
+                   AND (?5 = '' OR stream_name = ?5)
+                   AND timestamp >= ?6 AND timestamp <= ?7
+                 ORDER BY timestamp, id
+                 LIMIT ?8 OFFSET ?9",
+            )
+            .context(errors::ErrorKind::Cache)?;
+        let events = statement
+            .query_map(
+                params![
+                    key.profile,
+                    key.region,
+                    key.group_name,
+                    key.filter_key(),
+                    key.stream_key(),
+                    start,
+                    end,
+                    limit as i64,
+                    offset as i64
+                ],
+                |row| {
+                    let stream_name: String = row.get(0)?;
+                    Ok(LogEvent {
+                        event_id: None,
+                        stream_name: Some(stream_name).filter(|s| !s.is_empty()),
+                        timestamp: Utc.timestamp_millis(row.get(1)?),
+                        message: row.get(2)?,
+                        source: None,
+                    })
+                },
+            )
+            .context(errors::ErrorKind::Cache)?
+            .collect::<Result<Vec<_>, _>>()
+            .context(errors::ErrorKind::Cache)?;
+
+        Ok(events)
+    }
+
+    /// キャッシュ済みの全イベントから全文検索し、見つかったイベントを最大 `MAX_LIMIT` 件ずつ `handle` に渡す
+    ///
+    /// 同じイベントが別のフィルタパターンでも保存されている場合は1件にまとめ、
+    /// 取得元を `プロファイル/リージョン/ロググループ` のラベルとして付けて時系列順に返す。
+    /// クエリの構文が正しくない場合は `InvalidQuery` のエラーとする。
+    pub fn search<F>(
+        &self,
+        query: &str,
+        group_names: &[&str],
+        start: Option<i64>,
+        end: Option<i64>,
+        limit: Option<usize>,
+        mut handle: F,
+    ) -> Result<(), errors::Error>
+    where
+        F: FnMut(Vec<LogEvent>) -> Result<(), errors::Error>,
+    {
+        let mut values = vec![
+            Value::from(query.to_string()),
+            Value::from(start.unwrap_or(i64::MIN)),
+            Value::from(end.unwrap_or(i64::MAX)),
+            Value::from(limit.map_or(-1, |n| n as i64)),
+        ];
+        let group_condition = if group_names.is_empty() {
+            String::new()
+        } else {
+            let placeholders: Vec<String> = (0..group_names.len())
+                .map(|i| format!("?{}", values.len() + i + 1))
+                .collect();
+            values.extend(group_names.iter().map(|s| Value::from(s.to_string())));
+            format!("AND e.group_name IN ({})", placeholders.join(", "))
+        };
+
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT e.profile, e.region, e.group_name, e.stream_name, e.timestamp, e.message
+                 FROM events_fts JOIN events e ON e.id = events_fts.rowid
+                 WHERE events_fts MATCH ?1 AND e.timestamp >= ?2 AND e.timestamp <= ?3 {}
+                 GROUP BY e.profile, e.region, e.group_name, e.event_id
+                 ORDER BY e.timestamp, min(e.id)
+                 LIMIT ?4",
+                group_condition
+            ))
+            .context(errors::ErrorKind::Cache)?;
+        let rows = statement
+            .query_map(params_from_iter(values), |row| {
+                let profile: String = row.get(0)?;
+                let region: String = row.get(1)?;
+                let group_name: String = row.get(2)?;
+                let stream_name: String = row.get(3)?;
+                Ok(LogEvent {
+                    event_id: None,
+                    stream_name: Some(stream_name).filter(|s| !s.is_empty()),
+                    timestamp: Utc.timestamp_millis(row.get(4)?),
+                    message: row.get(5)?,
+                    source: Some(format!("{}/{}/{}", profile, region, group_name)),
+                })
+            })
+            .map_err(query_error)?;
+
+        let mut page = Vec::new();
+        for row in rows {
+            page.push(row.map_err(query_error)?);
+            if page.len() >= MAX_LIMIT {
+                handle(std::mem::take(&mut page))?;
+            }
+        }
+        if !page.is_empty() {
+            handle(page)?;
+        }
+        Ok(())
+    }
+}
+
+/// FTS5のクエリの構文エラーはキャッシュの異常と区別する
+///
+/// 検索を実行したときの `SQLITE_ERROR` はスキーマが揃っている限りクエリの誤りによるもの。
+fn query_error(e: rusqlite::Error) -> errors::Error {
+    let kind = match e {
+        rusqlite::Error::SqliteFailure(ref failure, _)
+            if failure.code == rusqlite::ErrorCode::Unknown =>
+        {
+            errors::ErrorKind::InvalidQuery
+        }
+        _ => errors::ErrorKind::Cache,
+    };
+    errors::Error::from(e.context(kind))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// CachedLogEventsReader
+//
+////////////////////////////////////////////////////////////////////////////////
+
+pub type ReaderFactory = dyn Fn(LogEventsRequest) -> Box<dyn LogEventsReader + Send> + Send + Sync;
+
+/// キャッシュにない期間だけをCloudWatch Logsから取得し、結果をキャッシュから読むリーダー
+///
+/// 最初の呼び出しで不足分を取得して保存し、以降はトークンをキャッシュ内の読み出し位置として使う。
+pub struct CachedLogEventsReader {
+    pub cache: Arc<EventCache>,
+    pub key: CacheKey,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    /// 不足している期間を読むためのリーダーを作る
+    pub reader_factory: Arc<ReaderFactory>,
+}
+
+impl CachedLogEventsReader {
+    fn load_page(
+        cache: &EventCache,
+        key: &CacheKey,
+        start: i64,
+        end: i64,
+        offset: usize,
+    ) -> Result<LogEventsResponse, errors::Error> {
+        let events = cache.load(key, start, end, offset, MAX_LIMIT)?;
+        let next_token = if events.len() == MAX_LIMIT {
+            Some((offset + events.len()).to_string())
+        } else {
+            None
+        };
+        Ok(LogEventsResponse { events, next_token })
+    }
+
+    /// 不足している期間を取得してページごとに保存し、期間全体を保存できたら取得済みとして記録する
+    ///
+    /// 途中で失敗した場合は取得済みとして記録しないので、次回に同じ期間を取得し直す。
+    fn fill(&self) -> Box<dyn Future<Item = (), Error = errors::Error> + Send> {
+        let start = self.start_time.timestamp_millis();
+        let end = self.end_time.timestamp_millis();
+        let gaps = match self.cache.missing_ranges(&self.key, start, end) {
+            Ok(gaps) => gaps,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let cache = self.cache.clone();
+        let key = self.key.clone();
+        let reader_factory = self.reader_factory.clone();
+        Box::new(
+            futures::stream::iter_ok(gaps).for_each(move |(gap_start, gap_end)| {
+                // 終了時刻を含まないAPIでも `gap_end` のイベントを取得できるよう1ミリ秒先まで指定し、超えた分は捨てる
+                let request = LogEventsRequest {
+                    start_time: Some(Utc.timestamp_millis(gap_start)),
+                    end_time: Some(Utc.timestamp_millis(gap_end + 1)),
+                    limit: None,
+                };
+                let page_cache = cache.clone();
+                let page_key = key.clone();
+                let cache = cache.clone();
+                let key = key.clone();
+                stream::create_log_events_stream(reader_factory(request))
+                    .for_each(move |mut res| {
+                        res.events
+                            .retain(|event| event.timestamp.timestamp_millis() <= gap_end);
+                        page_cache.store_events(&page_key, &res.events)
+                    })
+                    .and_then(move |_| cache.mark_covered(&key, gap_start, gap_end))
+            }),
+        )
+    }
+}
+
+impl LogEventsReader for CachedLogEventsReader {
+    fn read_log_events(&self, next_token: Option<String>) -> Box<LogEventResponseFuture> {
+        let start = self.start_time.timestamp_millis();
+        let end = self.end_time.timestamp_millis();
+        let cache = self.cache.clone();
+        let key = self.key.clone();
+
+        match next_token.and_then(|token| token.parse::<usize>().ok()) {
+            Some(offset) => Box::new(future::result(Self::load_page(
+                &cache, &key, start, end, offset,
+            ))),
+            None => Box::new(
+                self.fill()
+                    .and_then(move |_| Self::load_page(&cache, &key, start, end, 0)),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(stream_name: Option<&str>) -> CacheKey {
+        CacheKey {
+            profile: "default".to_string(),
+            region: "ap-northeast-1".to_string(),
+            group_name: "group".to_string(),
+            stream_name: stream_name.map(|s| s.to_string()),
+            filter_pattern: None,
+        }
+    }
+
+    fn open_cache(name: &str) -> (PathBuf, EventCache) {
+        let path = env::temp_dir().join(format!("cache-{}-{}.sqlite3", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let cache = EventCache::open(&path).unwrap();
+        (path, cache)
+    }
+
+    #[test]
+    fn missing_ranges_between_covered_ranges() {
+        // (取得済みの期間, [0, 99] のうち取得していない期間)
+        let cases = vec![
+            (Vec::<(i64, i64)>::new(), vec![(0, 99)]),
+            (vec![(0, 99)], Vec::new()),
+            (vec![(-10, 200)], Vec::new()),
+            (vec![(10, 19)], vec![(0, 9), (20, 99)]),
+            (vec![(0, 9), (90, 99)], vec![(10, 89)]),
+            // 重なる期間や順不同の期間もまとめて扱う
+            (
+                vec![(50, 59), (10, 29), (20, 39)],
+                vec![(0, 9), (40, 49), (60, 99)],
+            ),
+            // 隣り合う期間の間に隙間はない
+            (vec![(0, 49), (50, 99)], Vec::new()),
+            (vec![(-50, -1), (100, 150)], vec![(0, 99)]),
+            (vec![(99, 99)], vec![(0, 98)]),
+        ];
+        for (covered, expected) in cases {
+            assert_eq!(
+                missing_ranges(0, 99, covered.clone()),
+                expected,
+                "covered: {:?}",
+                covered
+            );
+        }
+    }
+
+    #[test]
+    fn coverage_of_the_whole_group_applies_to_streams() {
+        let (path, cache) = open_cache("coverage");
+
+        cache.mark_covered(&key(Some("app")), 1_000, 1_999).unwrap();
+        assert_eq!(
+            cache.missing_ranges(&key(None), 0, 2_999).unwrap(),
+            vec![(0, 2_999)]
+        );
+        assert_eq!(
+            cache.missing_ranges(&key(Some("app")), 0, 2_999).unwrap(),
+            vec![(0, 999), (2_000, 2_999)]
+        );
+
+        cache.mark_covered(&key(None), 0, 1_499).unwrap();
+        assert_eq!(
+            cache.missing_ranges(&key(Some("app")), 0, 2_999).unwrap(),
+            vec![(2_000, 2_999)]
+        );
+        assert_eq!(
+            cache
+                .missing_ranges(&key(Some("worker")), 0, 2_999)
+                .unwrap(),
+            vec![(1_500, 2_999)]
+        );
+
+        drop(cache);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recent_ranges_are_not_covered() {
+        let (path, cache) = open_cache("recent");
+
+        let now = Utc::now().timestamp_millis();
+        cache.mark_covered(&key(None), now - 60_000, now).unwrap();
+        assert_eq!(
+            cache.missing_ranges(&key(None), now - 60_000, now).unwrap(),
+            vec![(now - 60_000, now)]
+        );
+
+        drop(cache);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::Arc;
+
+use chrono::Duration;
+use futures::future::{self, Future};
+use rusoto_logs::CloudWatchLogsClient;
+
+use super::event::{LogEvent, LogEventsRequest};
+use super::reader::{GetLogEventsReader, LogEventsReader, MAX_LIMIT};
+use crate::errors;
+
+/// マッチしたイベントと同じタイムスタンプのイベントを取りこぼさないように余分に取得する件数
+const CONTEXT_MARGIN: usize = 100;
+
+pub type ContextFuture = dyn Future<Item = Vec<LogEvent>, Error = errors::Error> + Send;
+
+fn position_of(events: &[LogEvent], target: &LogEvent) -> Option<usize> {
+    events.iter().position(|event| event.is_same_event(target))
+}
+
+fn fetch_before(
+    client: Arc<CloudWatchLogsClient>,
+    group_name: String,
+    stream_name: String,
+    target: LogEvent,
+    count: usize,
+) -> Box<ContextFuture> {
+    if count == 0 {
+        return Box::new(future::ok(Vec::new()));
+    }
+
+    // 末尾から遡って取得するので、マッチしたイベント自身を含むように終了時刻を1ミリ秒進める
+    let reader = GetLogEventsReader {
+        client,
+        group_name,
+        stream_name,
+        start_from_head: Some(false),
+        request: LogEventsRequest {
+            start_time: None,
+            end_time: Some(target.timestamp + Duration::milliseconds(1)),
+            limit: Some((count + CONTEXT_MARGIN).min(MAX_LIMIT) as i64),
+        },
+    };
+
+    Box::new(reader.read_log_events(None).map(move |res| {
+        let events = res.events;
+        let end = position_of(&events, &target).unwrap_or_else(|| {
+            events
+                .iter()
+                .position(|event| event.timestamp >= target.timestamp)
+                .unwrap_or(events.len())
+        });
+        let start = end.saturating_sub(count);
+        events[start..end].to_vec()
+    }))
+}
+
+fn fetch_after(
+    client: Arc<CloudWatchLogsClient>,
+    group_name: String,
+    stream_name: String,
+    target: LogEvent,
+    count: usize,
+) -> Box<ContextFuture> {
+    if count == 0 {
+        return Box::new(future::ok(Vec::new()));
+    }
+
+    let reader = GetLogEventsReader {
+        client,
+        group_name,
+        stream_name,
+        start_from_head: Some(true),
+        request: LogEventsRequest {
+            start_time: Some(target.timestamp),
+            end_time: None,
+            limit: Some((count + CONTEXT_MARGIN).min(MAX_LIMIT) as i64),
+        },
+    };
+
+    Box::new(reader.read_log_events(None).map(move |res| {
+        let events = res.events;
+        let start = match position_of(&events, &target) {
+            Some(position) => position + 1,
+            None => events
+                .iter()
+                .position(|event| event.timestamp > target.timestamp)
+                .unwrap_or(events.len()),
+        };
+        let end = (start + count).min(events.len());
+        events[start..end].to_vec()
+    }))
+}
+
+/// マッチしたイベントの前後 `before`/`after` 件を同じストリームから取得し、時系列順に並べて返す
+pub fn fetch_context(
+    client: Arc<CloudWatchLogsClient>,
+    group_name: &str,
+    target: LogEvent,
+    before: usize,
+    after: usize,
+) -> Box<ContextFuture> {
+    let stream_name = match target.stream_name.clone() {
+        Some(stream_name) => stream_name,
+        None => return Box::new(future::ok(vec![target])),
+    };
+
+    let before_fut = fetch_before(
+        client.clone(),
+        group_name.to_string(),
+        stream_name.clone(),
+        target.clone(),
+        before,
+    );
+    let after_fut = fetch_after(
+        client,
+        group_name.to_string(),
+        stream_name,
+        target.clone(),
+        after,
+    );
+
+    Box::new(before_fut.join(after_fut).map(move |(before, after)| {
+        // 前後の行には取得元のラベルを引き継ぐ
+        let source = target.source.clone();
+        before
+            .into_iter()
+            .chain(Some(target))
+            .chain(after)
+            .map(|mut event| {
+                event.source = source.clone();
+                event
+            })
+            .collect()
+    }))
+}
+
+/// 直前に表示したグループと重なるイベントを取り除く
+///
+/// 重なりがあった場合は区切り線を挟まずに続けて表示できるよう `true` を返す。
+pub fn trim_overlap(previous: &[LogEvent], group: Vec<LogEvent>) -> (bool, Vec<LogEvent>) {
+    let size = group.len();
+    let trimmed: Vec<LogEvent> = group
+        .into_iter()
+        .filter(|event| position_of(previous, event).is_none())
+        .collect();
+
+    (trimmed.len() < size, trimmed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+
+    fn event(second: u32, message: &str) -> LogEvent {
+        LogEvent {
+            event_id: None,
+            message: message.to_string(),
+            timestamp: Utc.ymd(2019, 1, 2).and_hms(3, 4, second),
+            stream_name: Some("app".to_string()),
+            source: None,
+        }
+    }
+
+    #[test]
+    fn trim_overlap_removes_events_already_printed() {
+        let previous = vec![event(1, "a"), event(2, "b"), event(3, "c")];
+        let group = vec![event(2, "b"), event(3, "c"), event(4, "d")];
+
+        let (overlapped, trimmed) = trim_overlap(&previous, group);
+
+        assert!(overlapped);
+        let messages: Vec<&str> = trimmed.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, vec!["d"]);
+    }
+
+    #[test]
+    fn trim_overlap_keeps_separate_groups() {
+        let previous = vec![event(1, "a")];
+        // 同じメッセージでもタイムスタンプが違えば別のイベント
+        let group = vec![event(5, "a"), event(6, "b")];
+
+        let (overlapped, trimmed) = trim_overlap(&previous, group);
+
+        assert!(!overlapped);
+        assert_eq!(trimmed.len(), 2);
+    }
+
+    #[test]
+    fn trim_overlap_distinguishes_streams() {
+        let previous = vec![event(1, "a")];
+        let mut other = event(1, "a");
+        other.stream_name = Some("worker".to_string());
+
+        let (overlapped, trimmed) = trim_overlap(&previous, vec![other]);
+
+        assert!(!overlapped);
+        assert_eq!(trimmed.len(), 1);
+    }
+}
@@ -2,15 +2,28 @@ use chrono::prelude::*;
 
 #[derive(Debug, Clone)]
 pub struct LogEvent {
+    /// FilterLogEventsで取得した場合のイベントID
+    pub event_id: Option<String>,
     pub message: String,
     pub timestamp: DateTime<Utc>,
     pub stream_name: Option<String>,
+    pub source: Option<String>,
+}
+
+impl LogEvent {
+    /// 取得経路によらず同じイベントかどうかを判定する
+    pub fn is_same_event(&self, other: &LogEvent) -> bool {
+        self.timestamp == other.timestamp
+            && self.stream_name == other.stream_name
+            && self.message == other.message
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 pub struct LogEventsRequest {
     pub start_time: Option<DateTime<Utc>>,
     pub end_time: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
 }
 
 impl LogEventsRequest {
@@ -20,6 +33,37 @@ impl LogEventsRequest {
     pub fn end_time_value(&self) -> Option<i64> {
         self.end_time.map(|t| t.timestamp_millis())
     }
+
+    /// `[start_time, end_time]` を `shards` 個の重ならない期間に等分する
+    ///
+    /// 開始・終了時刻はどちらも境界を含むため、各期間の終了は次の期間の開始の1ミリ秒前になる。
+    pub fn split(
+        &self,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+        shards: usize,
+    ) -> Vec<Self> {
+        let start = start_time.timestamp_millis();
+        let end = end_time.timestamp_millis();
+        let shards = (shards as i64).clamp(1, (end - start + 1).max(1));
+        let width = (end - start + 1) / shards;
+
+        (0..shards)
+            .map(|i| {
+                let shard_start = start + width * i;
+                let shard_end = if i == shards - 1 {
+                    end
+                } else {
+                    shard_start + width - 1
+                };
+                LogEventsRequest {
+                    start_time: Some(Utc.timestamp_millis(shard_start)),
+                    end_time: Some(Utc.timestamp_millis(shard_end)),
+                    ..*self
+                }
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -27,3 +71,67 @@ pub struct LogEventsResponse {
     pub events: Vec<LogEvent>,
     pub next_token: Option<String>,
 }
+
+impl LogEventsResponse {
+    /// 取得元 (プロファイル/リージョン) のラベルを各イベントに付与する
+    pub fn with_source(mut self, source: &str) -> Self {
+        for event in self.events.iter_mut() {
+            event.source = Some(source.to_string());
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> LogEventsRequest {
+        LogEventsRequest {
+            start_time: None,
+            end_time: None,
+            limit: Some(100),
+        }
+    }
+
+    fn ranges(requests: &[LogEventsRequest]) -> Vec<(i64, i64)> {
+        requests
+            .iter()
+            .map(|r| (r.start_time_value().unwrap(), r.end_time_value().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn split_covers_the_range_without_overlap() {
+        let start = Utc.timestamp_millis(1_000);
+        let end = Utc.timestamp_millis(1_999);
+
+        let shards = request().split(start, end, 4);
+
+        assert_eq!(
+            ranges(&shards),
+            vec![
+                (1_000, 1_249),
+                (1_250, 1_499),
+                (1_500, 1_749),
+                (1_750, 1_999)
+            ]
+        );
+        assert!(shards.iter().all(|shard| shard.limit == Some(100)));
+    }
+
+    #[test]
+    fn split_gives_the_remainder_to_the_last_shard() {
+        let shards = request().split(Utc.timestamp_millis(0), Utc.timestamp_millis(9), 3);
+        assert_eq!(ranges(&shards), vec![(0, 2), (3, 5), (6, 9)]);
+    }
+
+    #[test]
+    fn split_does_not_create_empty_shards() {
+        let shards = request().split(Utc.timestamp_millis(0), Utc.timestamp_millis(2), 10);
+        assert_eq!(ranges(&shards), vec![(0, 0), (1, 1), (2, 2)]);
+
+        let shards = request().split(Utc.timestamp_millis(5), Utc.timestamp_millis(5), 0);
+        assert_eq!(ranges(&shards), vec![(5, 5)]);
+    }
+}
@@ -0,0 +1,65 @@
+use failure::ResultExt;
+use regex::{Regex, RegexBuilder};
+
+use super::event::{LogEvent, LogEventsResponse};
+use crate::errors;
+
+/// 取得したイベントを正規表現でさらに絞り込むフィルタ
+///
+/// `--filter-pattern` によるサーバー側の絞り込みの後に適用される。
+pub struct LocalFilter {
+    includes: Vec<Regex>,
+    excludes: Vec<Regex>,
+}
+
+pub fn build_regex(pattern: &str, ignore_case: bool) -> Result<Regex, errors::Error> {
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(ignore_case)
+        .build()
+        .context(errors::ErrorKind::InvalidRegex)?;
+
+    Ok(regex)
+}
+
+impl LocalFilter {
+    pub fn new(
+        includes: &[&str],
+        excludes: &[&str],
+        ignore_case: bool,
+    ) -> Result<Self, errors::Error> {
+        Ok(LocalFilter {
+            includes: includes
+                .iter()
+                .map(|pattern| build_regex(pattern, ignore_case))
+                .collect::<Result<_, _>>()?,
+            excludes: excludes
+                .iter()
+                .map(|pattern| build_regex(pattern, ignore_case))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.includes.is_empty() && self.excludes.is_empty()
+    }
+
+    /// `--grep` のいずれかにマッチし、かつ `--grep-v` のどれにもマッチしなければ真
+    pub fn matches(&self, event: &LogEvent) -> bool {
+        let message = event.message.as_str();
+        let included =
+            self.includes.is_empty() || self.includes.iter().any(|re| re.is_match(message));
+
+        included && !self.excludes.iter().any(|re| re.is_match(message))
+    }
+
+    pub fn apply(&self, res: LogEventsResponse) -> LogEventsResponse {
+        LogEventsResponse {
+            events: res
+                .events
+                .into_iter()
+                .filter(|event| self.matches(event))
+                .collect(),
+            next_token: res.next_token,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use ansi_term::{Color, Style};
+use regex::{self, Regex};
+
+use super::filter;
+use super::pattern::FilterPattern;
+use crate::errors;
+
+/// メッセージ中のマッチした部分を強調表示する
+#[derive(Clone)]
+pub struct Highlighter {
+    patterns: Vec<Regex>,
+    style: Style,
+}
+
+impl Default for Highlighter {
+    fn default() -> Self {
+        Highlighter {
+            patterns: Vec::new(),
+            style: Color::Red.bold(),
+        }
+    }
+}
+
+impl Highlighter {
+    pub fn new(
+        filter_expression: Option<&str>,
+        grep_patterns: &[&str],
+        ignore_case: bool,
+    ) -> Result<Self, errors::Error> {
+        let mut patterns = Vec::new();
+        // CloudWatch Logsの語句のマッチは大文字小文字を区別する
+        // ローカルで解析できないパターンは強調表示しない
+        if let Some(Ok(pattern)) = filter_expression.map(FilterPattern::parse) {
+            for term in pattern.terms() {
+                patterns.push(filter::build_regex(regex::escape(term).as_str(), false)?);
+            }
+        }
+        for pattern in grep_patterns.iter() {
+            patterns.push(filter::build_regex(pattern, ignore_case)?);
+        }
+
+        Ok(Highlighter {
+            patterns,
+            ..Default::default()
+        })
+    }
+
+    pub fn highlight(&self, text: &str) -> String {
+        let mut ranges: Vec<(usize, usize)> = self
+            .patterns
+            .iter()
+            .flat_map(|re| re.find_iter(text).map(|m| (m.start(), m.end())))
+            .filter(|(start, end)| start < end)
+            .collect();
+        if ranges.is_empty() {
+            return text.to_string();
+        }
+        ranges.sort();
+
+        // 重なり合う範囲をまとめてから色を付ける
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        let mut highlighted = String::new();
+        let mut position = 0;
+        for (start, end) in merged {
+            highlighted.push_str(&text[position..start]);
+            highlighted.push_str(&self.style.paint(&text[start..end]).to_string());
+            position = end;
+        }
+        highlighted.push_str(&text[position..]);
+        highlighted
+    }
+}
@@ -0,0 +1,331 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+use chrono::prelude::*;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::warn;
+
+use super::TZ_ASIA_TOKYO;
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Helper functions
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// `10M` や `512K` のようなサイズ表記をバイト数に変換する
+///
+/// 単位を掛けて `u64` に収まらない場合は `None` を返す。
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => (&s[..i], &s[i..]),
+        None => (s, ""),
+    };
+    let multiplier = match unit.to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+}
+
+fn current_hour() -> DateTime<FixedOffset> {
+    let now = Utc::now().with_timezone(&*TZ_ASIA_TOKYO);
+    now.date().and_hms(now.hour(), 0, 0)
+}
+
+/// 既存のファイルと重ならないパスを作る
+fn unique_path(base: String) -> PathBuf {
+    let mut path = PathBuf::from(base.as_str());
+    let mut n = 1;
+    while path.exists() || Path::new(&format!("{}.gz", path.display())).exists() {
+        path = PathBuf::from(format!("{}.{}", base, n));
+        n += 1;
+    }
+    path
+}
+
+fn compress_file(path: &Path) -> io::Result<()> {
+    let compressed = PathBuf::from(format!("{}.gz", path.display()));
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+
+    fs::remove_file(path)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// RotatingFile
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// サイズまたは時間でローテーションしながら書き込むファイル
+///
+/// ローテーションしたファイルは `PATH.<日時>` にリネームされ、必要に応じてgzip圧縮される。
+/// 圧縮は書き込みを止めないよう別スレッドで行う。
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    hourly: bool,
+    compress: bool,
+    file: BufWriter<File>,
+    size: u64,
+    opened_hour: DateTime<FixedOffset>,
+    compressing: Option<JoinHandle<()>>,
+}
+
+fn open_append(path: &Path) -> io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+impl RotatingFile {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        max_size: Option<u64>,
+        hourly: bool,
+        compress: bool,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (file, size) = open_append(&path)?;
+
+        Ok(RotatingFile {
+            path,
+            max_size,
+            hourly,
+            compress,
+            file,
+            size,
+            opened_hour: current_hour(),
+            compressing: None,
+        })
+    }
+
+    fn should_rotate(&self, incoming: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+
+        let oversized = match self.max_size {
+            Some(max_size) => self.size + incoming as u64 > max_size,
+            None => false,
+        };
+        oversized || (self.hourly && current_hour() != self.opened_hour)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let suffix = if self.hourly && self.max_size.is_none() {
+            self.opened_hour.format("%Y%m%d%H").to_string()
+        } else {
+            Utc::now()
+                .with_timezone(&*TZ_ASIA_TOKYO)
+                .format("%Y%m%d%H%M%S")
+                .to_string()
+        };
+        let rotated = unique_path(format!("{}.{}", self.path.display(), suffix));
+        fs::rename(&self.path, &rotated)?;
+
+        let (file, size) = open_append(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.opened_hour = current_hour();
+
+        if self.compress {
+            // 同時に圧縮するのは1ファイルまでにする
+            self.wait_compression();
+            self.compressing = Some(thread::spawn(move || {
+                if let Err(e) = compress_file(&rotated) {
+                    warn!("failed to compress {}: {}", rotated.display(), e);
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    fn wait_compression(&mut self) {
+        if let Some(handle) = self.compressing.take() {
+            if handle.join().is_err() {
+                warn!("the compression thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        self.wait_compression();
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+
+        // 1行が2つのファイルに分かれないよう、ローテーションの判定をした単位で書き切る
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Tee
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// 2つの出力先に同じ内容を書き込む
+pub struct Tee<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: Write, B: Write> Tee<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Tee { first, second }
+    }
+}
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.first.write_all(buf)?;
+        self.second.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// テストごとに空の作業ディレクトリを作る
+    fn work_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("output-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn rotated_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap() != "out.log")
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn parse_size_units() {
+        let cases = [
+            ("0", Some(0)),
+            ("100", Some(100)),
+            ("100B", Some(100)),
+            ("512K", Some(512 * 1024)),
+            ("512kb", Some(512 * 1024)),
+            ("10M", Some(10 * 1024 * 1024)),
+            ("2GB", Some(2 * 1024 * 1024 * 1024)),
+            (" 1M ", Some(1024 * 1024)),
+            ("", None),
+            ("M", None),
+            ("1.5M", None),
+            ("10T", None),
+            ("-1K", None),
+            ("18446744073709551615", Some(u64::MAX)),
+            ("18446744073709551615K", None),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(parse_size(s), *expected, "size: {:?}", s);
+        }
+    }
+
+    #[test]
+    fn rotates_before_exceeding_max_size() {
+        let dir = work_dir("size");
+        let path = dir.join("out.log");
+        {
+            let mut file = RotatingFile::open(&path, Some(10), false, false).unwrap();
+            file.write_all(b"1234\n").unwrap();
+            file.write_all(b"123\n").unwrap();
+            assert!(rotated_files(&dir).is_empty());
+
+            // 書き込むと10バイトを超えるので、その前にローテーションする
+            file.write_all(b"abc\n").unwrap();
+            file.flush().unwrap();
+        }
+
+        let rotated = rotated_files(&dir);
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(fs::read_to_string(&rotated[0]).unwrap(), "1234\n123\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "abc\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn does_not_rotate_an_empty_file() {
+        let dir = work_dir("empty");
+        let path = dir.join("out.log");
+        {
+            // 1行が上限を超えても、空のファイルには書き込む
+            let mut file = RotatingFile::open(&path, Some(4), false, false).unwrap();
+            file.write_all(b"longer than the limit\n").unwrap();
+        }
+
+        assert!(rotated_files(&dir).is_empty());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "longer than the limit\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compresses_rotated_files() {
+        let dir = work_dir("compress");
+        let path = dir.join("out.log");
+        {
+            let mut file = RotatingFile::open(&path, Some(4), false, true).unwrap();
+            file.write_all(b"first\n").unwrap();
+            file.write_all(b"second\n").unwrap();
+        }
+
+        // 破棄するときに圧縮の完了を待つ
+        let rotated = rotated_files(&dir);
+        assert_eq!(rotated.len(), 1);
+        assert!(rotated[0].to_string_lossy().ends_with(".gz"));
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(File::open(&rotated[0]).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "first\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
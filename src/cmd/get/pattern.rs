@@ -0,0 +1,1019 @@
+use failure::{format_err, ResultExt};
+use regex::Regex;
+use serde_json::Value;
+
+use super::event::LogEventsResponse;
+use crate::errors;
+
+/// CloudWatch Logsのフィルタパターンを解析し、ローカルでイベントに適用する
+///
+/// 次の3つの形式に対応する。
+///
+/// - 語句: `ERROR`, `"phrase with spaces"`, `?ERROR ?WARN`, `ERROR -debug`, `%ERR(OR)?%`
+/// - JSON: `{ $.level = "ERROR" && ($.latency > 100 || $.user IS NULL) }`
+/// - スペース区切り: `[ip, user, ..., status = 4*, bytes > 1000, method IN ["GET", "PUT"]]`
+///
+/// ローカルで評価できない構文は解析エラーになる。
+#[derive(Debug, Clone)]
+pub struct FilterPattern {
+    kind: PatternKind,
+}
+
+#[derive(Debug, Clone)]
+enum PatternKind {
+    /// 空のパターンはすべてのイベントに一致する
+    All,
+    Terms(Vec<Term>),
+    Json(Expr<JsonCondition>),
+    Delimited(Vec<Field>),
+}
+
+impl FilterPattern {
+    pub fn parse(pattern: &str) -> Result<Self, errors::Error> {
+        let kind = parse_pattern(pattern)
+            .map_err(|e| format_err!("'{}': {}", pattern, e))
+            .context(errors::ErrorKind::InvalidFilterPattern)?;
+
+        Ok(FilterPattern { kind })
+    }
+
+    pub fn matches(&self, message: &str) -> bool {
+        match self.kind {
+            PatternKind::All => true,
+            PatternKind::Terms(ref terms) => matches_terms(terms, message),
+            PatternKind::Json(ref expr) => match serde_json::from_str::<Value>(message.trim()) {
+                Ok(json) => expr.eval(&|condition| condition.eval(&json)),
+                Err(_) => false,
+            },
+            PatternKind::Delimited(ref fields) => matches_fields(fields, &split_fields(message)),
+        }
+    }
+
+    pub fn apply(&self, res: LogEventsResponse) -> LogEventsResponse {
+        LogEventsResponse {
+            events: res
+                .events
+                .into_iter()
+                .filter(|event| self.matches(event.message.as_str()))
+                .collect(),
+            next_token: res.next_token,
+        }
+    }
+
+    /// 強調表示に使う語句
+    ///
+    /// 除外条件や正規表現の語句、JSON形式・スペース区切り形式の条件は対象にしない。
+    pub fn terms(&self) -> Vec<&str> {
+        match self.kind {
+            PatternKind::Terms(ref terms) => terms
+                .iter()
+                .filter(|term| term.mode != TermMode::Excluded && term.regex.is_none())
+                .map(|term| term.text.as_str())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Result<PatternKind, String> {
+    let pattern = pattern.trim();
+    if pattern.is_empty() {
+        return Ok(PatternKind::All);
+    }
+
+    if pattern.starts_with('{') {
+        if !pattern.ends_with('}') || pattern.len() < 2 {
+            return Err("missing '}' at the end of the JSON pattern".to_string());
+        }
+        let mut parser = Parser::new(&pattern[1..pattern.len() - 1])?;
+        let expr = parser.parse_expr(&|parser: &mut Parser| parser.parse_json_condition())?;
+        parser.expect_end()?;
+        return Ok(PatternKind::Json(expr));
+    }
+
+    if pattern.starts_with('[') {
+        if !pattern.ends_with(']') || pattern.len() < 2 {
+            return Err("missing ']' at the end of the space-delimited pattern".to_string());
+        }
+        let mut parser = Parser::new(&pattern[1..pattern.len() - 1])?;
+        let fields = parser.parse_fields()?;
+        parser.expect_end()?;
+        return Ok(PatternKind::Delimited(fields));
+    }
+
+    parse_terms(pattern).map(PatternKind::Terms)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Terms
+//
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TermMode {
+    Required,
+    /// `?term` のいずれか1つに一致すればよい
+    Optional,
+    /// `-term` を含むイベントは除外する
+    Excluded,
+}
+
+#[derive(Debug, Clone)]
+struct Term {
+    text: String,
+    /// `%...%` で書いた語句はメッセージの一部に一致する正規表現とする
+    regex: Option<Regex>,
+    mode: TermMode,
+}
+
+/// `%` の次の文字から閉じる `%` までを正規表現として読む (`\%` は `%` そのもの)
+fn read_regex<I: Iterator<Item = char>>(chars: &mut I) -> Result<String, String> {
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some('%') => break,
+            Some('\\') => match chars.next() {
+                Some('%') => text.push('%'),
+                Some(c) => {
+                    text.push('\\');
+                    text.push(c);
+                }
+                None => text.push('\\'),
+            },
+            Some(c) => text.push(c),
+            None => return Err("unterminated regular expression".to_string()),
+        }
+    }
+    if text.is_empty() {
+        return Err("empty regular expression".to_string());
+    }
+    Ok(text)
+}
+
+fn build_regex(text: &str) -> Result<Regex, String> {
+    Regex::new(text).map_err(|e| format!("invalid regular expression '{}': {}", text, e))
+}
+
+fn parse_terms(pattern: &str) -> Result<Vec<Term>, String> {
+    let mut terms = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+
+        let (mode, first) = match c {
+            '?' => (TermMode::Optional, chars.next()),
+            '-' => (TermMode::Excluded, chars.next()),
+            c => (TermMode::Required, Some(c)),
+        };
+
+        let mut regex = None;
+        let text = match first {
+            Some('%') => {
+                let text = read_regex(&mut chars)?;
+                regex = Some(build_regex(text.as_str())?);
+                text
+            }
+            Some('"') => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => return Err("unterminated quoted term".to_string()),
+                    }
+                }
+                text
+            }
+            Some(c) if !c.is_whitespace() => {
+                let mut text = c.to_string();
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    if *c == '"' {
+                        return Err(format!("unexpected '\"' in term '{}'", text));
+                    }
+                    text.push(*c);
+                    chars.next();
+                }
+                text
+            }
+            _ => return Err(format!("missing term after '{}'", c)),
+        };
+
+        if text.is_empty() {
+            return Err("empty quoted term".to_string());
+        }
+        terms.push(Term { text, regex, mode });
+    }
+    Ok(terms)
+}
+
+/// すべての必須の語句を含み、除外する語句を含まず、`?` の語句があればそのいずれかを含む
+fn matches_terms(terms: &[Term], message: &str) -> bool {
+    let mut has_optional = false;
+    let mut optional_matched = false;
+    for term in terms.iter() {
+        let found = match term.regex {
+            Some(ref regex) => regex.is_match(message),
+            None => message.contains(term.text.as_str()),
+        };
+        match term.mode {
+            TermMode::Required if !found => return false,
+            TermMode::Excluded if found => return false,
+            TermMode::Optional => {
+                has_optional = true;
+                optional_matched |= found;
+            }
+            _ => {}
+        }
+    }
+    !has_optional || optional_matched
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Conditions
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// `&&` と `||` で組み合わせた条件 (`&&` が優先)
+#[derive(Debug, Clone)]
+enum Expr<C> {
+    And(Box<Expr<C>>, Box<Expr<C>>),
+    Or(Box<Expr<C>>, Box<Expr<C>>),
+    Condition(C),
+}
+
+impl<C> Expr<C> {
+    fn eval(&self, test: &dyn Fn(&C) -> bool) -> bool {
+        match self {
+            Expr::And(left, right) => left.eval(test) && right.eval(test),
+            Expr::Or(left, right) => left.eval(test) || right.eval(test),
+            Expr::Condition(condition) => test(condition),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// 比較する値。数値として解釈できる引用符なしの値は数値としても比較する。
+#[derive(Debug, Clone)]
+struct Operand {
+    text: String,
+    number: Option<f64>,
+    /// `%...%` で書いた値は値の一部に一致する正規表現とする
+    regex: Option<Regex>,
+}
+
+impl Operand {
+    fn compare(&self, operator: Operator, actual: &str) -> bool {
+        let actual_number = actual.trim().parse::<f64>().ok();
+        match operator {
+            Operator::Eq => self.equals(actual, actual_number),
+            Operator::Ne => !self.equals(actual, actual_number),
+            _ => match (actual_number, self.number) {
+                (Some(actual), Some(expected)) => match operator {
+                    Operator::Lt => actual < expected,
+                    Operator::Le => actual <= expected,
+                    Operator::Gt => actual > expected,
+                    _ => actual >= expected,
+                },
+                _ => false,
+            },
+        }
+    }
+
+    fn equals(&self, actual: &str, actual_number: Option<f64>) -> bool {
+        if let Some(ref regex) = self.regex {
+            return regex.is_match(actual);
+        }
+        match (self.number, actual_number) {
+            (Some(expected), Some(actual)) => (expected - actual).abs() < f64::EPSILON,
+            _ => matches_wildcard(self.text.as_str(), actual),
+        }
+    }
+}
+
+/// 演算子による比較、または `IN [...]`/`NOT IN [...]` による値の一覧との比較
+#[derive(Debug, Clone)]
+enum Comparison {
+    Compare(Operator, Operand),
+    /// 一覧のいずれかと等しい (`true` なら `NOT IN` でいずれとも等しくない)
+    In(Vec<Operand>, bool),
+}
+
+impl Comparison {
+    fn eval(&self, actual: &str) -> bool {
+        match self {
+            Comparison::Compare(operator, operand) => operand.compare(*operator, actual),
+            Comparison::In(operands, negated) => {
+                let actual_number = actual.trim().parse::<f64>().ok();
+                operands
+                    .iter()
+                    .any(|operand| operand.equals(actual, actual_number))
+                    != *negated
+            }
+        }
+    }
+}
+
+/// `*` を任意の文字列として `text` 全体と照合する
+fn matches_wildcard(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !text.starts_with(first) || text.len() < first.len() + last.len() {
+        return false;
+    }
+    let mut rest = &text[first.len()..];
+    for part in parts[1..parts.len() - 1].iter() {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[derive(Debug, Clone)]
+enum Selector {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone)]
+enum JsonTest {
+    Compare(Comparison),
+    IsNull,
+    IsTrue,
+    IsFalse,
+    NotExists,
+}
+
+#[derive(Debug, Clone)]
+struct JsonCondition {
+    selectors: Vec<Selector>,
+    test: JsonTest,
+}
+
+impl JsonCondition {
+    fn select<'a>(&self, json: &'a Value) -> Option<&'a Value> {
+        self.selectors
+            .iter()
+            .try_fold(json, |value, selector| match selector {
+                Selector::Key(key) => value.get(key.as_str()),
+                Selector::Index(index) => value.get(*index),
+            })
+    }
+
+    fn eval(&self, json: &Value) -> bool {
+        let value = self.select(json);
+        match self.test {
+            JsonTest::NotExists => value.is_none(),
+            JsonTest::IsNull => value.into_iter().any(|value| value.is_null()),
+            JsonTest::IsTrue => value.and_then(|value| value.as_bool()) == Some(true),
+            JsonTest::IsFalse => value.and_then(|value| value.as_bool()) == Some(false),
+            JsonTest::Compare(ref comparison) => {
+                let actual = match value {
+                    Some(Value::String(s)) => s.clone(),
+                    Some(Value::Number(n)) => n.to_string(),
+                    Some(Value::Bool(b)) => b.to_string(),
+                    _ => return false,
+                };
+                comparison.eval(actual.as_str())
+            }
+        }
+    }
+}
+
+fn parse_selector(text: &str) -> Result<Vec<Selector>, String> {
+    if !text.starts_with('$') {
+        return Err(format!("selector '{}' must start with '$'", text));
+    }
+
+    let mut selectors = Vec::new();
+    let mut rest = &text[1..];
+    while !rest.is_empty() {
+        if rest.starts_with('.') {
+            let end = rest[1..]
+                .find(&['.', '['][..])
+                .map_or(rest.len(), |i| i + 1);
+            if end == 1 {
+                return Err(format!("empty property name in selector '{}'", text));
+            }
+            selectors.push(Selector::Key(rest[1..end].to_string()));
+            rest = &rest[end..];
+        } else if rest.starts_with('[') {
+            let end = rest
+                .find(']')
+                .ok_or_else(|| format!("missing ']' in selector '{}'", text))?;
+            let index = rest[1..end]
+                .parse()
+                .map_err(|_| format!("invalid array index in selector '{}'", text))?;
+            selectors.push(Selector::Index(index));
+            rest = &rest[end + 1..];
+        } else {
+            return Err(format!("unexpected '{}' in selector '{}'", rest, text));
+        }
+    }
+    Ok(selectors)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Space-delimited fields
+//
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone)]
+enum Field {
+    /// `...` は任意の数のフィールドに一致する
+    Ellipsis,
+    /// 条件のないフィールドは任意の値に一致する
+    Named(Option<Expr<Comparison>>),
+}
+
+/// メッセージを空白で区切る。`"..."` と `[...]` で囲まれた部分は1つのフィールドとする。
+fn split_fields(message: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut rest = message.trim_start();
+    while !rest.is_empty() {
+        let close = match rest.chars().next() {
+            Some('"') => Some('"'),
+            Some('[') => Some(']'),
+            _ => None,
+        };
+        let (field, next) = match close.and_then(|c| rest[1..].find(c).map(|i| i + 1)) {
+            Some(end) => (&rest[1..end], &rest[end + 1..]),
+            None => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        fields.push(field);
+        rest = next.trim_start();
+    }
+    fields
+}
+
+fn matches_fields(fields: &[Field], values: &[&str]) -> bool {
+    match fields.split_first() {
+        None => values.is_empty(),
+        Some((Field::Ellipsis, rest)) => {
+            (0..=values.len()).any(|skip| matches_fields(rest, &values[skip..]))
+        }
+        Some((Field::Named(condition), rest)) => match values.split_first() {
+            Some((value, remaining)) => {
+                let matched = condition
+                    .iter()
+                    .all(|condition| condition.eval(&|comparison| comparison.eval(value)));
+                matched && matches_fields(rest, remaining)
+            }
+            None => false,
+        },
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Parser
+//
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Regex(String),
+    Operator(Operator),
+    And,
+    Or,
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+    Comma,
+}
+
+fn describe(token: Option<&Token>) -> String {
+    match token {
+        Some(Token::Word(s)) => format!("'{}'", s),
+        Some(Token::Quoted(s)) => format!("'\"{}\"'", s),
+        Some(Token::Regex(s)) => format!("'%{}%'", s),
+        Some(Token::Operator(_)) => "an operator".to_string(),
+        Some(Token::And) => "'&&'".to_string(),
+        Some(Token::Or) => "'||'".to_string(),
+        Some(Token::Open) => "'('".to_string(),
+        Some(Token::Close) => "')'".to_string(),
+        Some(Token::OpenBracket) => "'['".to_string(),
+        Some(Token::CloseBracket) => "']'".to_string(),
+        Some(Token::Comma) => "','".to_string(),
+        None => "the end of the pattern".to_string(),
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '[' => Token::OpenBracket,
+            ']' => Token::CloseBracket,
+            ',' => Token::Comma,
+            '%' => Token::Regex(read_regex(&mut chars)?),
+            '&' | '|' => {
+                if chars.next() != Some(c) {
+                    return Err(format!("expected '{}{}'", c, c));
+                }
+                if c == '&' {
+                    Token::And
+                } else {
+                    Token::Or
+                }
+            }
+            '=' => Token::Operator(Operator::Eq),
+            '!' | '<' | '>' => {
+                let with_eq = chars.peek() == Some(&'=');
+                if with_eq {
+                    chars.next();
+                }
+                Token::Operator(match (c, with_eq) {
+                    ('!', true) => Operator::Ne,
+                    ('<', false) => Operator::Lt,
+                    ('<', true) => Operator::Le,
+                    ('>', false) => Operator::Gt,
+                    ('>', true) => Operator::Ge,
+                    _ => return Err("expected '!='".to_string()),
+                })
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => return Err("unterminated quoted value".to_string()),
+                    }
+                }
+                Token::Quoted(text)
+            }
+            c => {
+                // セレクタの `$.items[0]` は1語とし、対応しない `]` は値の一覧の終わりとする
+                let mut text = c.to_string();
+                let mut depth = 0;
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()=!<>,&|\"".contains(c) {
+                        break;
+                    }
+                    match c {
+                        '[' => depth += 1,
+                        ']' if depth == 0 => break,
+                        ']' => depth -= 1,
+                        _ => (),
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+                Token::Word(text)
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// JSON形式とスペース区切り形式の括弧の内側を読む再帰下降パーサ
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Result<Self, String> {
+        Ok(Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect_end(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            token => Err(format!("unexpected {}", describe(token))),
+        }
+    }
+
+    fn next_word(&mut self, expected: &str) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(word),
+            token => Err(format!(
+                "expected {} but found {}",
+                expected,
+                describe(token.as_ref())
+            )),
+        }
+    }
+
+    fn parse_expr<C>(
+        &mut self,
+        condition: &dyn Fn(&mut Parser) -> Result<C, String>,
+    ) -> Result<Expr<C>, String> {
+        let mut expr = self.parse_and(condition)?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and(condition)?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and<C>(
+        &mut self,
+        condition: &dyn Fn(&mut Parser) -> Result<C, String>,
+    ) -> Result<Expr<C>, String> {
+        let mut expr = self.parse_primary(condition)?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_primary(condition)?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_primary<C>(
+        &mut self,
+        condition: &dyn Fn(&mut Parser) -> Result<C, String>,
+    ) -> Result<Expr<C>, String> {
+        if self.peek() != Some(&Token::Open) {
+            return condition(self).map(Expr::Condition);
+        }
+
+        self.next();
+        let expr = self.parse_expr(condition)?;
+        match self.next() {
+            Some(Token::Close) => Ok(expr),
+            token => Err(format!(
+                "expected ')' but found {}",
+                describe(token.as_ref())
+            )),
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Word(text)) => Ok(Operand {
+                number: text.parse().ok(),
+                text,
+                regex: None,
+            }),
+            Some(Token::Quoted(text)) => Ok(Operand {
+                text,
+                number: None,
+                regex: None,
+            }),
+            Some(Token::Regex(text)) => Ok(Operand {
+                regex: Some(build_regex(text.as_str())?),
+                text,
+                number: None,
+            }),
+            token => Err(format!(
+                "expected a value but found {}",
+                describe(token.as_ref())
+            )),
+        }
+    }
+
+    /// `[value, ...]` 形式の値の一覧を読む
+    fn parse_operand_list(&mut self) -> Result<Vec<Operand>, String> {
+        match self.next() {
+            Some(Token::OpenBracket) => (),
+            token => {
+                return Err(format!(
+                    "expected '[' but found {}",
+                    describe(token.as_ref())
+                ))
+            }
+        }
+
+        let mut operands = Vec::new();
+        loop {
+            operands.push(self.parse_operand()?);
+            match self.next() {
+                Some(Token::Comma) => (),
+                Some(Token::CloseBracket) => return Ok(operands),
+                token => {
+                    return Err(format!(
+                        "expected ',' or ']' but found {}",
+                        describe(token.as_ref())
+                    ))
+                }
+            }
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Comparison, String> {
+        let operator = match self.next() {
+            Some(Token::Operator(operator)) => operator,
+            Some(Token::Word(ref word)) if word == "IN" => {
+                return Ok(Comparison::In(self.parse_operand_list()?, false));
+            }
+            Some(Token::Word(ref word)) if word == "NOT" => {
+                return match self.next_word("IN")?.as_str() {
+                    "IN" => Ok(Comparison::In(self.parse_operand_list()?, true)),
+                    word => Err(format!("expected IN after NOT, not '{}'", word)),
+                };
+            }
+            token => {
+                return Err(format!(
+                    "expected an operator but found {}",
+                    describe(token.as_ref())
+                ))
+            }
+        };
+
+        let operand = self.parse_operand()?;
+        if operator != Operator::Eq && operator != Operator::Ne && operand.number.is_none() {
+            return Err(format!("'{}' is not a number to compare", operand.text));
+        }
+        Ok(Comparison::Compare(operator, operand))
+    }
+
+    fn parse_json_condition(&mut self) -> Result<JsonCondition, String> {
+        let selectors = parse_selector(self.next_word("a selector")?.as_str())?;
+
+        let test = match self.peek() {
+            Some(Token::Word(word)) if word == "IS" => {
+                self.next();
+                match self.next_word("NULL, TRUE or FALSE")?.as_str() {
+                    "NULL" => JsonTest::IsNull,
+                    "TRUE" => JsonTest::IsTrue,
+                    "FALSE" => JsonTest::IsFalse,
+                    word => {
+                        return Err(format!(
+                            "expected NULL, TRUE or FALSE after IS, not '{}'",
+                            word
+                        ))
+                    }
+                }
+            }
+            // `NOT IN` は比較として読む
+            Some(Token::Word(word))
+                if word == "NOT"
+                    && self.tokens.get(self.position + 1)
+                        == Some(&Token::Word("EXISTS".to_string())) =>
+            {
+                self.position += 2;
+                JsonTest::NotExists
+            }
+            _ => JsonTest::Compare(self.parse_comparison()?),
+        };
+        Ok(JsonCondition { selectors, test })
+    }
+
+    fn parse_fields(&mut self) -> Result<Vec<Field>, String> {
+        let mut fields = Vec::new();
+        loop {
+            let name = self.next_word("a field name")?;
+            if name == "..." {
+                fields.push(Field::Ellipsis);
+            } else {
+                // 条件はフィールド自身の名前で書く (`status = 4* || status = 5*`)
+                let condition = match self.peek() {
+                    Some(Token::Comma) | None => None,
+                    _ => {
+                        self.position -= 1;
+                        let field_name = name.clone();
+                        Some(self.parse_expr(&|parser: &mut Parser| {
+                            let name = parser.next_word("a field name")?;
+                            if name != field_name {
+                                return Err(format!(
+                                    "the condition of field '{}' refers to '{}'",
+                                    field_name, name
+                                ));
+                            }
+                            parser.parse_comparison()
+                        })?)
+                    }
+                };
+                fields.push(Field::Named(condition));
+            }
+
+            match self.peek() {
+                Some(Token::Comma) => {
+                    self.next();
+                }
+                _ => return Ok(fields),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_matches(cases: &[(&str, &str, bool)]) {
+        for (pattern, message, expected) in cases.iter() {
+            let parsed = FilterPattern::parse(pattern)
+                .unwrap_or_else(|e| panic!("failed to parse {}: {}", pattern, e));
+            assert_eq!(
+                parsed.matches(message),
+                *expected,
+                "pattern: {}, message: {}",
+                pattern,
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn terms() {
+        assert_matches(&[
+            ("", "anything", true),
+            ("ERROR", "[ERROR] failed", true),
+            ("ERROR", "[error] failed", false),
+            ("ERROR failed", "[ERROR] failed", true),
+            ("ERROR timeout", "[ERROR] failed", false),
+            ("?ERROR ?WARN", "[WARN] slow", true),
+            ("?ERROR ?WARN", "[INFO] ok", false),
+            ("ERROR -debug", "[ERROR] failed", true),
+            ("ERROR -debug", "[ERROR] debug dump", false),
+            ("-debug", "[INFO] ok", true),
+            ("ERROR ?db ?cache", "[ERROR] cache miss", true),
+            ("ERROR ?db ?cache", "[ERROR] failed", false),
+            ("%ERR(OR)?%", "[ERR] failed", true),
+            ("%ERR(OR)?%", "[WARN] failed", false),
+            ("-%user-\\d+%", "user-12 logged in", false),
+            ("%100\\%%", "cpu 100% used", true),
+        ]);
+    }
+
+    #[test]
+    fn quoted_terms() {
+        assert_matches(&[
+            (
+                "\"connection reset\"",
+                "error: connection reset by peer",
+                true,
+            ),
+            ("\"connection reset\"", "error: connection was reset", false),
+            ("\"say \\\"hi\\\"\"", "they say \"hi\" to you", true),
+            ("?\"a b\" ?\"c d\"", "x c d y", true),
+            ("-\"not found\" GET", "GET /index 404 not found", false),
+        ]);
+    }
+
+    #[test]
+    fn json_selectors() {
+        let message = r#"{"level": "ERROR", "latency": 150, "user": null, "ok": false,
+            "request": {"method": "GET", "path": "/api/items"}, "tags": ["a", "b"]}"#;
+        assert_matches(&[
+            (r#"{ $.level = "ERROR" }"#, message, true),
+            (r#"{ $.level != "ERROR" }"#, message, false),
+            (r#"{ $.level = ERR* }"#, message, true),
+            (r#"{ $.request.path = "/api/*" }"#, message, true),
+            (
+                r#"{ $.request.path = %^/api/(items|users)$% }"#,
+                message,
+                true,
+            ),
+            (r#"{ $.tags[1] = "b" }"#, message, true),
+            (r#"{ $.tags[2] = "b" }"#, message, false),
+            ("{ $.latency > 100 }", message, true),
+            ("{ $.latency >= 150 && $.latency <= 150 }", message, true),
+            ("{ $.latency < 100 }", message, false),
+            ("{ $.latency = 150.0 }", message, true),
+            (r#"{ $.level = "WARN" || $.latency > 100 }"#, message, true),
+            (
+                r#"{ ($.level = "WARN" || $.latency > 100) && $.ok IS TRUE }"#,
+                message,
+                false,
+            ),
+            (r#"{ $.level IN ["WARN", "ERROR"] }"#, message, true),
+            (r#"{ $.level NOT IN ["WARN", "ERROR"] }"#, message, false),
+            ("{ $.latency IN [100, 150] }", message, true),
+            (
+                r#"{ $.request.method IN [%^P%, "DELETE"] }"#,
+                message,
+                false,
+            ),
+            (r#"{ $.level = "ERROR" }"#, "[ERROR] not json", false),
+        ]);
+    }
+
+    #[test]
+    fn json_is_and_not_exists() {
+        let message = r#"{"user": null, "ok": false, "admin": true}"#;
+        assert_matches(&[
+            ("{ $.user IS NULL }", message, true),
+            ("{ $.ok IS NULL }", message, false),
+            ("{ $.missing IS NULL }", message, false),
+            ("{ $.ok IS FALSE }", message, true),
+            ("{ $.admin IS TRUE }", message, true),
+            ("{ $.admin IS FALSE }", message, false),
+            ("{ $.missing NOT EXISTS }", message, true),
+            ("{ $.user NOT EXISTS }", message, false),
+        ]);
+    }
+
+    #[test]
+    fn space_delimited_fields() {
+        let message =
+            r#"127.0.0.1 - frank [10/Oct/2000:13:25:15 -0700] "GET /index.html HTTP/1.0" 404 1534"#;
+        assert_matches(&[
+            (
+                "[ip, identity, user, timestamp, request, status, bytes]",
+                message,
+                true,
+            ),
+            (
+                "[ip, identity, user, timestamp, request, status]",
+                message,
+                false,
+            ),
+            ("[ip, identity, user = frank, ...]", message, true),
+            ("[ip, identity, user = bob, ...]", message, false),
+            ("[ip = 127.0.0.*, ...]", message, true),
+            ("[..., status = 4*, bytes > 1000]", message, true),
+            ("[..., status = 4*, bytes > 2000]", message, false),
+            ("[..., status = 2* || status = 4*, bytes]", message, true),
+            ("[..., status IN [400, 404], bytes]", message, true),
+            ("[..., status NOT IN [400, 404], bytes]", message, false),
+            ("[..., request = \"GET *\", status, bytes]", message, true),
+            (
+                "[..., request = %HTTP/1\\.[01]$%, status, bytes]",
+                message,
+                true,
+            ),
+            ("[ip, ..., bytes]", message, true),
+            (
+                "[..., ip, identity, user, timestamp, request, status, bytes]",
+                message,
+                true,
+            ),
+        ]);
+    }
+
+    #[test]
+    fn rejects_unsupported_patterns() {
+        for pattern in [
+            "\"unterminated",
+            "ERR\"OR",
+            "?",
+            "%unterminated",
+            "%(%",
+            "{ $.level = \"ERROR\"",
+            "{ level = \"ERROR\" }",
+            "{ $.level IS MAYBE }",
+            "{ $.level NOT LIKE \"ERROR\" }",
+            "{ $.level IN \"ERROR\" }",
+            "{ $.level IN [\"ERROR\" }",
+            "{ $.latency > slow }",
+            "{ $.level = \"ERROR\" & $.latency > 1 }",
+            "[ip, user",
+            "[ip, user = bob || ip = 1]",
+        ]
+        .iter()
+        {
+            assert!(
+                FilterPattern::parse(pattern).is_err(),
+                "pattern: {}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn terms_for_highlight() {
+        let pattern = FilterPattern::parse("ERROR ?db \"time out\" -debug %E\\d+%").unwrap();
+        assert_eq!(pattern.terms(), vec!["ERROR", "db", "time out"]);
+    }
+}
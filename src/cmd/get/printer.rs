@@ -1,47 +1,130 @@
+use std::io::{self, BufWriter, Stdout, Write};
+use std::str::FromStr;
+
 use ansi_term::Color;
 
+use super::highlight::Highlighter;
 use super::TZ_ASIA_TOKYO;
 use crate::cmd::get::event::LogEvent;
 
+/// `--color` で指定する色付けの方針
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ColorMode {
+    Always,
+    Never,
+    Auto,
+}
+
+impl ColorMode {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => atty::is(atty::Stream::Stdout),
+        }
+    }
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            "auto" => Ok(ColorMode::Auto),
+            _ => Err(format!("Unknown color mode: {}", s)),
+        }
+    }
+}
+
+/// 標準出力をバッファリングして書き出すデフォルトの出力先
+///
+/// `Printer` は非同期タスク間を移動するため `StdoutLock` は保持できないが、
+/// ページごとにまとめて書き出すのでロックの取得もページごとに1回になる。
+pub type StdoutSink = BufWriter<Stdout>;
+
+pub fn stdout_sink() -> StdoutSink {
+    BufWriter::new(io::stdout())
+}
+
 pub trait Printer: Send {
-    fn print_events(&self, events: &Vec<LogEvent>);
+    fn print_events(&mut self, events: &Vec<LogEvent>) -> io::Result<()>;
+
+    /// 出力先
+    fn sink(&mut self) -> &mut dyn Write;
+
+    /// 前後の行を表示するときのグループ間の区切り
+    fn print_separator(&mut self) -> io::Result<()> {
+        self.puts("--")
+    }
 
-    fn puts(&self, text: &str) {
-        if text.ends_with("\n") {
-            print!("{}", text);
+    fn puts(&mut self, text: &str) -> io::Result<()> {
+        // 1行を1回の書き込みで出力する
+        if text.ends_with('\n') {
+            self.sink().write_all(text.as_bytes())
         } else {
-            println!("{}", text);
+            self.sink().write_all(format!("{}\n", text).as_bytes())
         }
     }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink().flush()
+    }
 }
 
 #[derive(Clone)]
-pub struct MessagePrinter {}
+pub struct MessagePrinter<W = StdoutSink> {
+    out: W,
+    highlighter: Option<Highlighter>,
+}
 
-unsafe impl Send for MessagePrinter {}
+impl<W: Write + Send> MessagePrinter<W> {
+    /// 色付けが無効な場合は `highlighter` に `None` を渡す
+    pub fn new(out: W, highlighter: Option<Highlighter>) -> Self {
+        MessagePrinter { out, highlighter }
+    }
+}
 
 impl Default for MessagePrinter {
     fn default() -> Self {
-        MessagePrinter {}
+        MessagePrinter::new(stdout_sink(), None)
     }
 }
 
-impl Printer for MessagePrinter {
-    fn print_events(&self, events: &Vec<LogEvent>) {
+impl<W: Write + Send> Printer for MessagePrinter<W> {
+    fn print_events(&mut self, events: &Vec<LogEvent>) -> io::Result<()> {
         for event in events.iter() {
-            self.puts(format!("{}", event.message).as_str());
+            let message = match self.highlighter.as_ref() {
+                Some(highlighter) => highlighter.highlight(event.message.as_str()),
+                None => event.message.clone(),
+            };
+            self.puts(message.as_str())?;
         }
+        Ok(())
+    }
+
+    fn sink(&mut self) -> &mut dyn Write {
+        &mut self.out
     }
 }
 
 #[derive(Clone)]
-pub struct LogPrinter {
+pub struct LogPrinter<W = StdoutSink> {
+    out: W,
     enable_color: bool,
+    highlighter: Highlighter,
 }
 
-unsafe impl Send for LogPrinter {}
+impl<W: Write + Send> LogPrinter<W> {
+    pub fn new(out: W, enable_color: bool, highlighter: Highlighter) -> Self {
+        LogPrinter {
+            out,
+            enable_color,
+            highlighter,
+        }
+    }
 
-impl LogPrinter {
     fn decorate(&self, text: String) -> String {
         if self.enable_color {
             Color::Green.paint(&text).to_string()
@@ -49,21 +132,31 @@ impl LogPrinter {
             text
         }
     }
+
+    fn emphasize(&self, message: &str) -> String {
+        if self.enable_color {
+            self.highlighter.highlight(message)
+        } else {
+            message.to_string()
+        }
+    }
 }
 
 impl Default for LogPrinter {
     fn default() -> Self {
-        LogPrinter {
-            enable_color: atty::is(atty::Stream::Stdout),
-        }
+        LogPrinter::new(
+            stdout_sink(),
+            ColorMode::Auto.enabled(),
+            Highlighter::default(),
+        )
     }
 }
 
-impl Printer for LogPrinter {
-    fn print_events(&self, events: &Vec<LogEvent>) {
+impl<W: Write + Send> Printer for LogPrinter<W> {
+    fn print_events(&mut self, events: &Vec<LogEvent>) -> io::Result<()> {
         let tz = TZ_ASIA_TOKYO.clone();
         for event in events.iter() {
-            let prefix = self.decorate(format!(
+            let mut prefix = self.decorate(format!(
                 "[{}]",
                 // event.timestamp.with_timezone(&tz).to_rfc3339()
                 event
@@ -71,8 +164,63 @@ impl Printer for LogPrinter {
                     .with_timezone(&tz)
                     .format("%Y-%m-%d %H:%M:%S"),
             ));
+            if let Some(source) = event.source.as_ref() {
+                prefix = format!("{} {}", prefix, self.decorate(format!("[{}]", source)));
+            }
+
+            let message = self.emphasize(event.message.as_str());
+            self.puts(format!("{} {}", prefix, message).as_str())?;
+        }
+        Ok(())
+    }
+
+    fn sink(&mut self) -> &mut dyn Write {
+        &mut self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
 
-            self.puts(format!("{} {}", prefix, event.message).as_str());
+    fn event(message: &str, source: Option<&str>) -> LogEvent {
+        LogEvent {
+            event_id: None,
+            message: message.to_string(),
+            timestamp: Utc.ymd(2019, 1, 2).and_hms(3, 4, 5),
+            stream_name: None,
+            source: source.map(|s| s.to_string()),
         }
     }
+
+    #[test]
+    fn message_printer_writes_one_line_per_event() {
+        let mut printer = MessagePrinter::new(Vec::new(), None);
+        printer
+            .print_events(&vec![event("first", None), event("second\n", None)])
+            .unwrap();
+        printer.print_separator().unwrap();
+
+        assert_eq!(
+            String::from_utf8(printer.out).unwrap(),
+            "first\nsecond\n--\n"
+        );
+    }
+
+    #[test]
+    fn log_printer_prefixes_jst_timestamp_and_source() {
+        let mut printer = LogPrinter::new(Vec::new(), false, Highlighter::default());
+        printer
+            .print_events(&vec![
+                event("hello", None),
+                event("world", Some("dev/ap-northeast-1")),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(printer.out).unwrap(),
+            "[2019-01-02 12:04:05] hello\n[2019-01-02 12:04:05] [dev/ap-northeast-1] world\n"
+        );
+    }
 }
@@ -1,5 +1,11 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Lines, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
 use chrono::prelude::*;
 use failure::{format_err, ResultExt};
+use flate2::read::MultiGzDecoder;
 use futures::prelude::*;
 use rusoto_logs::{
     CloudWatchLogs, CloudWatchLogsClient, FilterLogEventsError, FilterLogEventsRequest,
@@ -8,8 +14,12 @@ use rusoto_logs::{
 };
 
 use super::event::{LogEvent, LogEventsRequest, LogEventsResponse};
+use crate::cmd::export::EventRecord;
 use crate::errors;
 
+/// GetLogEvents/FilterLogEventsの1回の呼び出しで取得できる最大件数
+pub const MAX_LIMIT: usize = 10_000;
+
 ////////////////////////////////////////////////////////////////////////////////
 //
 // Helper functions
@@ -48,9 +58,10 @@ pub trait LogEventsReader {
 ////////////////////////////////////////////////////////////////////////////////
 
 pub struct GetLogEventsReader {
-    pub client: CloudWatchLogsClient,
+    pub client: Arc<CloudWatchLogsClient>,
     pub group_name: String,
     pub stream_name: String,
+    pub start_from_head: Option<bool>,
     pub request: LogEventsRequest,
 }
 
@@ -61,14 +72,24 @@ impl LogEventsReader for GetLogEventsReader {
             end_time: self.request.end_time_value(),
             log_group_name: self.group_name.clone(),
             log_stream_name: self.stream_name.clone(),
+            start_from_head: self.start_from_head,
+            limit: self.request.limit,
             next_token,
-            ..Default::default()
         };
         let stream_name = self.stream_name.clone();
+        // 末尾から読む場合は遡る方向のトークンを次のページとして扱う
+        let backward = self.start_from_head == Some(false);
         Box::new(
             self.client
                 .get_log_events(get_request)
-                .map(move |res| LogEventsResponse::from((res, stream_name)))
+                .map(move |res| {
+                    let backward_token = res.next_backward_token.clone();
+                    let mut res = LogEventsResponse::from((res, stream_name));
+                    if backward {
+                        res.next_token = backward_token;
+                    }
+                    res
+                })
                 .map_err(|e| errors::Error::from(e)),
         )
     }
@@ -77,9 +98,11 @@ impl LogEventsReader for GetLogEventsReader {
 impl From<(OutputLogEvent, String)> for LogEvent {
     fn from((event, stream_name): (OutputLogEvent, String)) -> Self {
         LogEvent {
+            event_id: None,
             message: event.message.unwrap(),
             timestamp: from_epoch_millis(event.timestamp.unwrap()),
             stream_name: Some(stream_name),
+            source: None,
         }
     }
 }
@@ -128,10 +151,10 @@ impl From<GetLogEventsError> for errors::Error {
 ////////////////////////////////////////////////////////////////////////////////
 
 pub struct FilterLogEventsReader {
-    pub client: CloudWatchLogsClient,
+    pub client: Arc<CloudWatchLogsClient>,
     pub group_name: String,
     pub stream_names: Option<Vec<String>>,
-    pub filter_expression: String,
+    pub filter_expression: Option<String>,
     pub request: LogEventsRequest,
 }
 
@@ -142,7 +165,8 @@ impl LogEventsReader for FilterLogEventsReader {
             log_stream_names: self.stream_names.clone(),
             start_time: self.request.start_time_value(),
             end_time: self.request.end_time_value(),
-            filter_pattern: Some(self.filter_expression.clone()),
+            filter_pattern: self.filter_expression.clone(),
+            limit: self.request.limit,
             next_token,
             ..Default::default()
         };
@@ -159,9 +183,11 @@ impl LogEventsReader for FilterLogEventsReader {
 impl From<FilteredLogEvent> for LogEvent {
     fn from(event: FilteredLogEvent) -> Self {
         LogEvent {
+            event_id: event.event_id,
             message: event.message.unwrap(),
             timestamp: from_epoch_millis(event.timestamp.unwrap()),
             stream_name: event.log_stream_name,
+            source: None,
         }
     }
 }
@@ -188,3 +214,196 @@ impl From<FilterLogEventsError> for errors::Error {
         )
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// FileLogEventsReader
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// ディレクトリが指定された場合はその下のアーカイブファイルを再帰的に集める
+pub fn collect_archive_files<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>, errors::Error> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if entry_path.is_dir() {
+            files.extend(collect_archive_files(&entry_path)?);
+            continue;
+        }
+        match entry_path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") | Some("ndjson") => files.push(entry_path),
+            _ => (),
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// ファイルの形式
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum FileFormat {
+    /// `export` で書き出した1行1イベントのNDJSON
+    Ndjson,
+    /// CloudWatch LogsのS3エクスポート (`<RFC3339のタイムスタンプ> <メッセージ>`)
+    S3Export,
+}
+
+fn parse_s3_export_line(line: &str) -> Option<(DateTime<Utc>, &str)> {
+    let mut parts = line.splitn(2, ' ');
+    let timestamp = DateTime::parse_from_rfc3339(parts.next()?).ok()?;
+    Some((timestamp.with_timezone(&Utc), parts.next().unwrap_or("")))
+}
+
+/// ファイルを開く。gzip圧縮されている場合は展開しながら読む。
+pub fn open_file<P: AsRef<Path>>(path: P) -> Result<Box<dyn BufRead + Send>, errors::Error> {
+    let path = path.as_ref();
+    let mut magic = [0u8; 2];
+    let compressed = {
+        let mut file = File::open(path)?;
+        file.read(&mut magic)? == 2 && magic == [0x1f, 0x8b]
+    };
+
+    let file = File::open(path)?;
+    Ok(if compressed {
+        Box::new(BufReader::new(MultiGzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    })
+}
+
+/// `export` で書き出したNDJSON、またはCloudWatch LogsのS3エクスポートのファイルを1イベントずつ読む
+///
+/// gzip圧縮の有無はファイルの先頭で判定する。
+/// 形式はファイル名 (`.ndjson`/`.ndjson.gz`) から、わからなければ最初の行から判定し、ファイルの途中では変えない。
+/// S3エクスポートのファイルにはストリーム名が含まれないため、親ディレクトリ名をストリーム名とする。
+pub struct FileLogEventsReader {
+    path: PathBuf,
+    lines: Lines<Box<dyn BufRead + Send>>,
+    format: Option<FileFormat>,
+    stream_name: Option<String>,
+    /// 続きの行があるかもしれないS3エクスポートのイベント
+    pending: Option<LogEvent>,
+}
+
+impl FileLogEventsReader {
+    pub fn open(path: PathBuf) -> Result<Self, errors::Error> {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let format = if file_name.ends_with(".ndjson") || file_name.ends_with(".ndjson.gz") {
+            Some(FileFormat::Ndjson)
+        } else {
+            None
+        };
+        let stream_name = path
+            .parent()
+            .and_then(|parent| parent.file_name())
+            .map(|name| name.to_string_lossy().to_string());
+
+        Ok(FileLogEventsReader {
+            lines: open_file(&path)?.lines(),
+            path,
+            format,
+            stream_name,
+            pending: None,
+        })
+    }
+
+    fn parse_record(&self, line: &str) -> Result<LogEvent, errors::Error> {
+        let record: EventRecord = serde_json::from_str(line)
+            .map_err(|e| format_err!("{}: {}", self.path.display(), e))
+            .context(errors::ErrorKind::InvalidArchive)?;
+        Ok(LogEvent::from(record))
+    }
+}
+
+impl Iterator for FileLogEventsReader {
+    type Item = Result<LogEvent, errors::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => return Some(Err(errors::Error::from(e))),
+                None => return self.pending.take().map(Ok),
+            };
+            if self.format.is_none() && line.trim().is_empty() {
+                continue;
+            }
+
+            let format = *self.format.get_or_insert(if line.starts_with('{') {
+                FileFormat::Ndjson
+            } else {
+                FileFormat::S3Export
+            });
+            match format {
+                FileFormat::Ndjson => {
+                    if !line.trim().is_empty() {
+                        return Some(self.parse_record(line.as_str()));
+                    }
+                }
+                FileFormat::S3Export => {
+                    if let Some((timestamp, message)) = parse_s3_export_line(line.as_str()) {
+                        let event = LogEvent {
+                            event_id: None,
+                            message: message.to_string(),
+                            timestamp,
+                            stream_name: self.stream_name.clone(),
+                            source: None,
+                        };
+                        if let Some(previous) = self.pending.replace(event) {
+                            return Some(Ok(previous));
+                        }
+                    } else if let Some(pending) = self.pending.as_mut() {
+                        // タイムスタンプで始まらない行は直前のイベントの続きとみなす
+                        pending.message.push('\n');
+                        pending.message.push_str(line.as_str());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// ページの番号をトークンとして `pages` を順に返すリーダー
+    ///
+    /// GetLogEventsと同じく、最後のページでは受け取ったトークンをそのまま返す。
+    pub struct PagedReader {
+        pub pages: Vec<Vec<LogEvent>>,
+        /// 呼び出された回数
+        pub reads: Arc<AtomicUsize>,
+    }
+
+    impl PagedReader {
+        pub fn new(pages: Vec<Vec<LogEvent>>) -> Self {
+            PagedReader {
+                pages,
+                reads: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    impl LogEventsReader for PagedReader {
+        fn read_log_events(&self, next_token: Option<String>) -> Box<LogEventResponseFuture> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let index: usize = next_token.map_or(0, |token| token.parse().unwrap());
+            let next_index = (index + 1).min(self.pages.len() - 1);
+            Box::new(futures::future::ok(LogEventsResponse {
+                events: self.pages[index].clone(),
+                next_token: Some(next_index.to_string()),
+            }))
+        }
+    }
+}
@@ -1,13 +1,21 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use futures::future::{self, Loop};
 use futures::prelude::*;
 use futures::stream::{self, Stream};
+use futures::sync::mpsc;
+use tokio::timer::Delay;
 
-use super::event::LogEventsResponse;
-use super::reader::LogEventsReader;
+use super::event::{LogEvent, LogEventsRequest, LogEventsResponse};
+use super::reader::{FileLogEventsReader, LogEventsReader};
 use crate::errors;
 
 #[derive(Debug)]
 enum StreamState {
-    Initial,
+    Initial(Option<String>),
     Running(Option<String>),
     Complete,
 }
@@ -17,32 +25,502 @@ pub type LogEventResponseStream = Stream<Item = LogEventsResponse, Error = error
 pub fn create_log_events_stream(
     reader: Box<LogEventsReader + Send>,
 ) -> Box<LogEventResponseStream> {
-    Box::new(stream::unfold(StreamState::Initial, move |state| {
-        let (has_next, next_token) = match state {
-            StreamState::Initial => (true, None),
-            StreamState::Running(token) => (token.is_some(), token),
-            StreamState::Complete => (false, None),
-        };
+    create_log_events_stream_from(reader, None)
+}
+
+/// 中断したところから読み直せるよう、最初のページのトークンを指定してストリームを作る
+pub fn create_log_events_stream_from(
+    reader: Box<dyn LogEventsReader + Send>,
+    initial_token: Option<String>,
+) -> Box<LogEventResponseStream> {
+    Box::new(stream::unfold(
+        StreamState::Initial(initial_token),
+        move |state| {
+            let (has_next, next_token) = match state {
+                StreamState::Initial(token) => (true, token),
+                StreamState::Running(token) => (token.is_some(), token),
+                StreamState::Complete => (false, None),
+            };
+
+            let current_token = next_token.clone().unwrap_or(String::new());
+            if has_next {
+                let fut = reader
+                    .read_log_events(next_token)
+                    .map(move |res| {
+                        let next_token = res.next_token.clone();
+                        let next_state = match next_token.as_ref() {
+                            Some(s) if s.as_str() == current_token.as_str() => {
+                                StreamState::Complete
+                            }
+                            Some(s) => StreamState::Running(Some(s.to_string())),
+                            None => StreamState::Complete,
+                        };
+
+                        (res, next_state)
+                    })
+                    .map_err(errors::Error::from);
+
+                Some(fut)
+            } else {
+                None
+            }
+        },
+    ))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// File
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// ファイルから読んだイベントを1回に返す件数
+const FILE_PAGE_SIZE: usize = 1_000;
+
+/// ファイルを少しずつ読み、期間内のイベントを `FILE_PAGE_SIZE` 件ずつ返す
+///
+/// ファイル全体は読み込まないので、並べ替えはページの中でだけ行う。
+/// `export` やS3エクスポートのファイルはおおむね時系列順に書かれている前提とする。
+pub fn create_file_log_events_stream(
+    path: PathBuf,
+    request: LogEventsRequest,
+) -> Box<LogEventResponseStream> {
+    let events = future::lazy(move || FileLogEventsReader::open(path))
+        .map(stream::iter_result)
+        .flatten_stream()
+        .filter(move |event| {
+            request.start_time.into_iter().all(|t| t <= event.timestamp)
+                && request.end_time.into_iter().all(|t| event.timestamp <= t)
+        });
+
+    Box::new(events.chunks(FILE_PAGE_SIZE).map(|mut events| {
+        events.sort_by_key(|event| event.timestamp);
+        LogEventsResponse {
+            events,
+            next_token: None,
+        }
+    }))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Prefetch
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// 元のストリームを別タスクで読み進め、最大 `capacity` ページを先に取得しておく受信側のストリームを返す
+///
+/// tokio::spawnを呼ぶのでランタイム上で呼び出す必要がある。
+fn spawn_log_events_stream(
+    stream: Box<LogEventResponseStream>,
+    capacity: usize,
+) -> Box<LogEventResponseStream> {
+    // mpsc::channelは送信側の数だけ余分にバッファするので1つ減らしておく
+    let (sender, receiver) = mpsc::channel(capacity.saturating_sub(1));
+
+    let producer = stream
+        .then(Ok::<_, mpsc::SendError<_>>)
+        .forward(sender)
+        .map(|_| ())
+        .map_err(|_| ()); // 受信側が先に終了した場合
+    tokio::spawn(producer);
+
+    Box::new(
+        receiver
+            .map_err(|_| unreachable!("mpsc::Receiver never fails"))
+            .and_then(|result| result),
+    )
+}
+
+/// 元のストリームを別タスクで読み進め、表示している間に次のページを取得しておく
+///
+/// 先読みするのは最大 `capacity` ページまでで、それ以上は消費されるまで次のリクエストを出さない。
+pub fn prefetch_log_events_stream(
+    stream: Box<LogEventResponseStream>,
+    capacity: usize,
+) -> Box<LogEventResponseStream> {
+    // tokio::spawnはランタイム上でしか呼べないので最初にポーリングされるまで遅延させる
+    let receiver =
+        future::lazy(move || Ok::<_, errors::Error>(spawn_log_events_stream(stream, capacity)));
 
-        let current_token = next_token.clone().unwrap_or(String::new());
-        if has_next {
-            let fut = reader
-                .read_log_events(next_token)
-                .map(move |res| {
-                    let next_token = res.next_token.clone();
-                    let next_state = match next_token.as_ref() {
-                        Some(s) if s.as_str() == current_token.as_str() => StreamState::Complete,
-                        Some(s) => StreamState::Running(Some(s.to_string())),
-                        None => StreamState::Complete,
-                    };
-
-                    (res, next_state)
+    Box::new(receiver.flatten_stream())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Sharded
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// 読み始めた期間ごとに先読みしておくページ数
+const SHARD_PREFETCH_PAGES: usize = 2;
+
+/// 期間ごとに分割したリーダーを最大 `concurrency` 個ずつ並行して読み、期間の順にまとめて返す
+///
+/// 後続の期間は `SHARD_PREFETCH_PAGES` ページまで先読みした時点で、消費されるまで次のリクエストを出さない。
+/// 途中でストリームを破棄した場合は読みかけの期間もそこで止まる。
+pub fn create_sharded_log_events_stream(
+    readers: Vec<Box<dyn LogEventsReader + Send>>,
+    concurrency: usize,
+) -> Box<LogEventResponseStream> {
+    Box::new(
+        stream::iter_ok(readers)
+            .map(|reader| {
+                future::lazy(move || {
+                    Ok::<_, errors::Error>(spawn_log_events_stream(
+                        create_log_events_stream(reader),
+                        SHARD_PREFETCH_PAGES,
+                    ))
                 })
-                .map_err(errors::Error::from);
+            })
+            .buffered(concurrency.max(1))
+            .flatten(),
+    )
+}
 
-            Some(fut)
-        } else {
+////////////////////////////////////////////////////////////////////////////////
+//
+// Watch / Tail
+//
+////////////////////////////////////////////////////////////////////////////////
+
+pub type LogEventsFuture = dyn Future<Item = Vec<LogEvent>, Error = errors::Error> + Send;
+
+/// 終端に達した後も `interval` ごとに同じトークンで読み直し続けるストリームを作る
+///
+/// 次のページのトークンで続きを読めるGetLogEventsでのみ使える。
+pub fn create_watch_stream(
+    reader: Box<dyn LogEventsReader + Send>,
+    interval: Duration,
+) -> Box<LogEventResponseStream> {
+    Box::new(stream::unfold(None, move |token: Option<String>| {
+        let current_token = token.clone();
+        let fut = reader.read_log_events(token).and_then(move |res| {
+            let next_token = res.next_token.clone().or_else(|| current_token.clone());
+            let wait: Box<dyn Future<Item = (), Error = errors::Error> + Send> =
+                if current_token.is_some() && next_token == current_token {
+                    // 新しいイベントがないので少し待ってから読み直す
+                    Box::new(
+                        Delay::new(Instant::now() + interval)
+                            .map_err(|e| errors::Error::from(e.context(errors::ErrorKind::Timer))),
+                    )
+                } else {
+                    Box::new(future::ok(()))
+                };
+
+            wait.map(move |_| (res, next_token))
+        });
+
+        Some(fut)
+    }))
+}
+
+/// 末尾からページを遡り、最後の `count` 件を時系列順に集める
+pub fn collect_tail(reader: Box<dyn LogEventsReader + Send>, count: usize) -> Box<LogEventsFuture> {
+    Box::new(future::loop_fn(
+        (None, Vec::new()),
+        move |(token, collected): (Option<String>, Vec<LogEvent>)| {
+            let current_token = token.clone();
+            reader.read_log_events(token).map(move |res| {
+                let mut events = res.events;
+                events.extend(collected);
+
+                let exhausted = match res.next_token.as_ref() {
+                    Some(next_token) => Some(next_token) == current_token.as_ref(),
+                    None => true,
+                };
+                if exhausted || events.len() >= count {
+                    let start = events.len().saturating_sub(count);
+                    Loop::Break(events.split_off(start))
+                } else {
+                    Loop::Continue((res.next_token, events))
+                }
+            })
+        },
+    ))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// LimitedLogEventsStream
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// `limit` 件のイベントを返した時点で終了するストリーム
+///
+/// 終了後は元のストリームをポーリングしないので、それ以降のページは取得されない。
+struct LimitedLogEventsStream {
+    inner: Box<LogEventResponseStream>,
+    remaining: usize,
+}
+
+impl Stream for LimitedLogEventsStream {
+    type Item = LogEventsResponse;
+    type Error = errors::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.remaining == 0 {
+            return Ok(Async::Ready(None));
+        }
+
+        match self.inner.poll()? {
+            Async::Ready(Some(mut res)) => {
+                res.events.truncate(self.remaining);
+                self.remaining -= res.events.len();
+                Ok(Async::Ready(Some(res)))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+pub fn limit_log_events_stream(
+    stream: Box<LogEventResponseStream>,
+    limit: usize,
+) -> Box<LogEventResponseStream> {
+    Box::new(LimitedLogEventsStream {
+        inner: stream,
+        remaining: limit,
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// MergedLogEventsStream
+//
+////////////////////////////////////////////////////////////////////////////////
+
+struct MergeSource {
+    stream: Box<LogEventResponseStream>,
+    buffer: VecDeque<LogEvent>,
+    done: bool,
+}
+
+/// 複数のストリームのイベントをタイムスタンプ順に並べ替えながら1本にまとめる
+///
+/// 各ストリームは時系列順にイベントを返す前提で、全ストリームの先読み分が揃った範囲だけを出力する。
+/// `flush_interval` を指定した場合は、新しいイベントを待っているストリームがあっても
+/// 先読み済みのイベントをその間隔ごとに出力する。
+struct MergedLogEventsStream {
+    sources: Vec<MergeSource>,
+    flush_interval: Option<Duration>,
+    flush_timer: Option<Delay>,
+}
+
+impl MergedLogEventsStream {
+    fn fill_buffers(&mut self) -> Result<bool, errors::Error> {
+        let mut ready = true;
+        for source in self.sources.iter_mut() {
+            while !source.done && source.buffer.is_empty() {
+                match source.stream.poll()? {
+                    Async::Ready(Some(res)) => source.buffer.extend(res.events),
+                    Async::Ready(None) => source.done = true,
+                    Async::NotReady => {
+                        ready = false;
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(ready)
+    }
+
+    /// 先読み済みのイベントがあり、`flush_interval` が経過していれば `true` を返す
+    fn poll_flush_timer(&mut self) -> Result<bool, errors::Error> {
+        let interval = match self.flush_interval {
+            Some(interval) => interval,
+            None => return Ok(false),
+        };
+        if self.sources.iter().all(|source| source.buffer.is_empty()) {
+            self.flush_timer = None;
+            return Ok(false);
+        }
+
+        let timer = self
+            .flush_timer
+            .get_or_insert_with(|| Delay::new(Instant::now() + interval));
+        match timer
+            .poll()
+            .map_err(|e| errors::Error::from(e.context(errors::ErrorKind::Timer)))?
+        {
+            Async::Ready(()) => {
+                self.flush_timer = None;
+                Ok(true)
+            }
+            Async::NotReady => Ok(false),
+        }
+    }
+
+    fn take_ordered_events(&mut self, flush: bool) -> Vec<LogEvent> {
+        // 終端に達していないストリームの先読み分の最後より後のイベントはまだ出せない
+        let boundary = if flush {
             None
+        } else {
+            self.sources
+                .iter()
+                .filter(|source| !source.done)
+                .filter_map(|source| source.buffer.back().map(|event| event.timestamp))
+                .min()
+        };
+
+        let mut events = Vec::new();
+        loop {
+            let next = self
+                .sources
+                .iter_mut()
+                .filter(|source| !source.buffer.is_empty())
+                .min_by_key(|source| source.buffer[0].timestamp);
+            let source = match next {
+                Some(source) => source,
+                None => break,
+            };
+            if let Some(boundary) = boundary {
+                if source.buffer[0].timestamp > boundary {
+                    break;
+                }
+            }
+            events.push(source.buffer.pop_front().unwrap());
         }
-    }))
+        events
+    }
+}
+
+impl Stream for MergedLogEventsStream {
+    type Item = LogEventsResponse;
+    type Error = errors::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let flush = if self.fill_buffers()? {
+            self.flush_timer = None;
+            false
+        } else if self.poll_flush_timer()? {
+            true
+        } else {
+            return Ok(Async::NotReady);
+        };
+
+        let events = self.take_ordered_events(flush);
+        if events.is_empty() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::Ready(Some(LogEventsResponse {
+                events,
+                next_token: None,
+            })))
+        }
+    }
+}
+
+fn merge_sources(streams: Vec<Box<LogEventResponseStream>>) -> Vec<MergeSource> {
+    streams
+        .into_iter()
+        .map(|stream| MergeSource {
+            stream,
+            buffer: VecDeque::new(),
+            done: false,
+        })
+        .collect()
+}
+
+pub fn merge_log_events_streams(
+    streams: Vec<Box<LogEventResponseStream>>,
+) -> Box<LogEventResponseStream> {
+    Box::new(MergedLogEventsStream {
+        sources: merge_sources(streams),
+        flush_interval: None,
+        flush_timer: None,
+    })
+}
+
+/// 終わりのないストリームをまとめる
+///
+/// 新しいイベントのないストリームを待ち続けないよう、`interval` ごとに先読み済みのイベントを出力する。
+/// そのため、間隔をまたいで遅れて届いたイベントはタイムスタンプ順にならないことがある。
+pub fn merge_watch_log_events_streams(
+    streams: Vec<Box<LogEventResponseStream>>,
+    interval: Duration,
+) -> Box<LogEventResponseStream> {
+    Box::new(MergedLogEventsStream {
+        sources: merge_sources(streams),
+        flush_interval: Some(interval),
+        flush_timer: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::get::reader::tests::PagedReader;
+    use chrono::prelude::*;
+    use std::sync::atomic::Ordering;
+
+    fn event(second: u32) -> LogEvent {
+        LogEvent {
+            event_id: None,
+            message: second.to_string(),
+            timestamp: Utc.ymd(2019, 1, 2).and_hms(3, 4, second),
+            stream_name: None,
+            source: None,
+        }
+    }
+
+    fn messages(events: &[LogEvent]) -> Vec<String> {
+        events.iter().map(|event| event.message.clone()).collect()
+    }
+
+    /// 末尾から遡る順にページを並べたリーダー
+    fn backward_reader() -> Box<dyn LogEventsReader + Send> {
+        Box::new(PagedReader::new(vec![
+            vec![event(4), event(5)],
+            vec![event(2), event(3)],
+            vec![event(0), event(1)],
+        ]))
+    }
+
+    #[test]
+    fn collect_tail_stops_when_enough_events_are_collected() {
+        let events = collect_tail(backward_reader(), 3).wait().unwrap();
+        assert_eq!(messages(&events), vec!["3", "4", "5"]);
+    }
+
+    #[test]
+    fn collect_tail_returns_all_events_when_exhausted() {
+        let events = collect_tail(backward_reader(), 10).wait().unwrap();
+        assert_eq!(messages(&events), vec!["0", "1", "2", "3", "4", "5"]);
+    }
+
+    #[test]
+    fn collect_tail_with_zero_count() {
+        let events = collect_tail(backward_reader(), 0).wait().unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn limited_stream_truncates_the_last_page() {
+        let reader = PagedReader::new(vec![
+            vec![event(0), event(1)],
+            vec![event(2), event(3)],
+            vec![event(4), event(5)],
+        ]);
+        let stream = limit_log_events_stream(create_log_events_stream(Box::new(reader)), 3);
+
+        let pages: Vec<Vec<String>> = stream
+            .map(|res| messages(&res.events))
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(pages, vec![vec!["0", "1"], vec!["2"]]);
+    }
+
+    #[test]
+    fn limited_stream_does_not_read_after_the_limit() {
+        let reader = PagedReader::new(vec![vec![event(0), event(1)], vec![event(2)]]);
+        let reads = reader.reads.clone();
+        let stream = limit_log_events_stream(create_log_events_stream(Box::new(reader)), 2);
+
+        let pages = stream.collect().wait().unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+    }
 }
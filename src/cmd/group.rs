@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use failure::{format_err, ResultExt};
+use log::info;
+use rusoto_logs::{
+    CloudWatchLogs, CloudWatchLogsClient, CreateLogGroupRequest, DeleteLogGroupError,
+    DeleteLogGroupRequest, DeleteRetentionPolicyError, DeleteRetentionPolicyRequest,
+    DescribeLogGroupsError, DescribeLogGroupsRequest, LogGroup, PutRetentionPolicyError,
+    PutRetentionPolicyRequest, TagLogGroupError, TagLogGroupRequest, UntagLogGroupError,
+    UntagLogGroupRequest,
+};
+
+use crate::cmd::{self, Target};
+use crate::errors;
+
+/// PutRetentionPolicyで指定できる保持期間 (日)
+pub const RETENTION_DAYS: &[i64] = &[
+    1, 3, 5, 7, 14, 30, 60, 90, 120, 150, 180, 365, 400, 545, 731, 1096, 1827, 2192, 2557, 2922,
+    3288, 3653,
+];
+
+/// 保持期間の指定を解析する。`never` は無期限 (`None`)。
+pub fn parse_retention(s: &str) -> Option<Option<i64>> {
+    if s == "never" {
+        return Some(None);
+    }
+    s.parse::<i64>()
+        .ok()
+        .filter(|days| RETENTION_DAYS.contains(days))
+        .map(Some)
+}
+
+pub fn validate_retention(s: String) -> Result<(), String> {
+    parse_retention(s.as_str()).map(|_| ()).ok_or_else(|| {
+        format!(
+            "'{}' is not a valid retention. Use 'never' or one of {}",
+            s,
+            RETENTION_DAYS
+                .iter()
+                .map(|days| days.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    })
+}
+
+fn validate_tag(s: String) -> Result<(), String> {
+    match s.find('=') {
+        Some(i) if i > 0 => Ok(()),
+        _ => Err(format!("'{}' is not in the form of KEY=VALUE", s)),
+    }
+}
+
+fn parse_tags(matches: &ArgMatches) -> HashMap<String, String> {
+    matches
+        .values_of("TAG")
+        .map(|values| {
+            values
+                .map(|tag| {
+                    let mut parts = tag.splitn(2, '=');
+                    let key = parts.next().unwrap().to_string();
+                    (key, parts.next().unwrap_or("").to_string())
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn group_arg() -> Arg<'static, 'static> {
+    Arg::with_name("GROUP_NAME")
+        .help("The name of the log group")
+        .short("g")
+        .long("group")
+        .required(true)
+        .takes_value(true)
+        .value_name("GROUP_NAME")
+}
+
+pub fn yes_arg() -> Arg<'static, 'static> {
+    Arg::with_name("YES")
+        .help("Do not ask for confirmation.")
+        .short("y")
+        .long("yes")
+}
+
+fn tag_arg() -> Arg<'static, 'static> {
+    Arg::with_name("TAG")
+        .help("The tag to set in the form of KEY=VALUE. Can be given multiple times.")
+        .long("tag")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .validator(validate_tag)
+        .value_name("KEY=VALUE")
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Manage log groups")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("create")
+                .about("Create a log group")
+                .arg(group_arg())
+                .arg(
+                    Arg::with_name("RETENTION")
+                        .help("The retention in days")
+                        .long("retention")
+                        .takes_value(true)
+                        .validator(validate_retention)
+                        .value_name("DAYS"),
+                )
+                .arg(
+                    Arg::with_name("KMS_KEY_ID")
+                        .help("The ARN of the KMS key to encrypt the log data")
+                        .long("kms-key-id")
+                        .takes_value(true)
+                        .value_name("KEY_ARN"),
+                )
+                .arg(tag_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Delete a log group and all of its events")
+                .arg(group_arg())
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("set-retention")
+                .about("Set how long the events of a log group are kept")
+                .arg(group_arg())
+                .arg(
+                    Arg::with_name("DAYS")
+                        .help("The retention in days, or 'never' to keep the events forever")
+                        .required(true)
+                        .validator(validate_retention)
+                        .index(1),
+                )
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("tag")
+                .about("Add or update the tags of a log group")
+                .arg(group_arg())
+                .arg(tag_arg().required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("untag")
+                .about("Remove tags from a log group")
+                .arg(group_arg())
+                .arg(
+                    Arg::with_name("KEY")
+                        .help("The keys of the tags to remove")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Actions
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// 名前が `prefix` で始まるロググループをすべて取得する
+pub fn describe_log_groups(
+    client: &CloudWatchLogsClient,
+    prefix: Option<&str>,
+) -> Result<Vec<LogGroup>, errors::Error> {
+    let mut groups = Vec::new();
+    let mut next_token = None;
+    loop {
+        let res = client
+            .describe_log_groups(DescribeLogGroupsRequest {
+                limit: None,
+                log_group_name_prefix: prefix.map(|s| s.to_string()),
+                next_token,
+            })
+            .sync()?;
+        groups.extend(res.log_groups.unwrap_or_default());
+
+        next_token = res.next_token;
+        if next_token.is_none() {
+            return Ok(groups);
+        }
+    }
+}
+
+fn create(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME").unwrap();
+    let tags = parse_tags(matches);
+    target
+        .client
+        .create_log_group(CreateLogGroupRequest {
+            log_group_name: group_name.to_string(),
+            kms_key_id: matches.value_of("KMS_KEY_ID").map(|s| s.to_string()),
+            tags: if tags.is_empty() { None } else { Some(tags) },
+        })
+        .sync()?;
+    println!("Created {} in {}", group_name, target.label());
+
+    if let Some(Some(days)) = matches.value_of("RETENTION").and_then(parse_retention) {
+        put_retention(target, group_name, Some(days))?;
+    }
+    Ok(())
+}
+
+fn delete(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME").unwrap();
+    let message = format!(
+        "Delete {} in {} and all of its events?",
+        group_name,
+        target.label()
+    );
+    if !cmd::confirm(message.as_str(), matches.is_present("YES"))? {
+        return Ok(());
+    }
+
+    target
+        .client
+        .delete_log_group(DeleteLogGroupRequest {
+            log_group_name: group_name.to_string(),
+        })
+        .sync()?;
+    println!("Deleted {} in {}", group_name, target.label());
+    Ok(())
+}
+
+/// 保持期間を設定する。`None` の場合は保持期間の設定を削除して無期限にする。
+pub fn put_retention(
+    target: &Target,
+    group_name: &str,
+    days: Option<i64>,
+) -> Result<(), errors::Error> {
+    match days {
+        Some(days) => {
+            target
+                .client
+                .put_retention_policy(PutRetentionPolicyRequest {
+                    log_group_name: group_name.to_string(),
+                    retention_in_days: days,
+                })
+                .sync()?;
+            println!(
+                "Set the retention of {} in {} to {} days",
+                group_name,
+                target.label(),
+                days
+            );
+        }
+        None => {
+            target
+                .client
+                .delete_retention_policy(DeleteRetentionPolicyRequest {
+                    log_group_name: group_name.to_string(),
+                })
+                .sync()?;
+            println!(
+                "Removed the retention of {} in {}",
+                group_name,
+                target.label()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn set_retention(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME").unwrap();
+    let days = parse_retention(matches.value_of("DAYS").unwrap()).unwrap();
+
+    // 保持期間を短くすると古いイベントが削除されるので確認する
+    if let Some(days) = days {
+        let message = format!(
+            "Events of {} in {} older than {} days will be deleted. Continue?",
+            group_name,
+            target.label(),
+            days
+        );
+        if !cmd::confirm(message.as_str(), matches.is_present("YES"))? {
+            return Ok(());
+        }
+    }
+
+    put_retention(target, group_name, days)
+}
+
+fn tag(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME").unwrap();
+    let tags = parse_tags(matches);
+    let count = tags.len();
+    target
+        .client
+        .tag_log_group(TagLogGroupRequest {
+            log_group_name: group_name.to_string(),
+            tags,
+        })
+        .sync()?;
+    println!("Set {} tags on {} in {}", count, group_name, target.label());
+    Ok(())
+}
+
+fn untag(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME").unwrap();
+    let keys: Vec<String> = matches
+        .values_of("KEY")
+        .unwrap()
+        .map(|s| s.to_string())
+        .collect();
+    let count = keys.len();
+    target
+        .client
+        .untag_log_group(UntagLogGroupRequest {
+            log_group_name: group_name.to_string(),
+            tags: keys,
+        })
+        .sync()?;
+    println!(
+        "Removed {} tags from {} in {}",
+        count,
+        group_name,
+        target.label()
+    );
+    Ok(())
+}
+
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("parse group options");
+    let target = cmd::single_target(targets)?;
+
+    info!("invoke group commands");
+    match matches.subcommand() {
+        ("create", Some(m)) => create(&target, m),
+        ("delete", Some(m)) => delete(&target, m),
+        ("set-retention", Some(m)) => set_retention(&target, m),
+        ("tag", Some(m)) => tag(&target, m),
+        ("untag", Some(m)) => untag(&target, m),
+        (name, _) => Err(format_err!("Unknown group command: '{}'", name))
+            .context(errors::ErrorKind::NoSubCommand)?,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Errors
+//
+////////////////////////////////////////////////////////////////////////////////
+
+errors::impl_from_rusoto_error!(
+    DeleteLogGroupError,
+    DeleteRetentionPolicyError,
+    DescribeLogGroupsError,
+    PutRetentionPolicyError,
+    TagLogGroupError,
+    UntagLogGroupError,
+);
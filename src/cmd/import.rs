@@ -0,0 +1,459 @@
+use std::io::BufRead;
+use std::path::Path;
+
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{debug, info, warn};
+use regex::Regex;
+use rusoto_logs::InputLogEvent;
+
+use crate::cmd::get::{filter, reader, TZ_ASIA_TOKYO};
+use crate::cmd::put::writer::LogEventsWriter;
+use crate::cmd::{self, Target};
+use crate::errors;
+
+pub struct ImportOptions<'a> {
+    group_name: &'a str,
+    stream_name: &'a str,
+    create: bool,
+    files: Vec<&'a str>,
+    time_format: &'a str,
+    time_regex: Option<&'a str>,
+    record_start: Option<&'a str>,
+}
+
+impl<'a> From<&'a ArgMatches<'a>> for ImportOptions<'a> {
+    fn from(matches: &'a ArgMatches<'a>) -> Self {
+        ImportOptions {
+            group_name: matches.value_of("GROUP_NAME").unwrap(),
+            stream_name: matches.value_of("STREAM_NAME").unwrap(),
+            create: matches.is_present("CREATE"),
+            files: matches.values_of("FILE").unwrap().collect(),
+            time_format: matches.value_of("TIME_FORMAT").unwrap(),
+            time_regex: matches.value_of("TIME_REGEX"),
+            record_start: matches.value_of("RECORD_START"),
+        }
+    }
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Import local log files (plain or gzip-compressed) into a log stream")
+        .arg(
+            Arg::with_name("GROUP_NAME")
+                .help("The name of the log group")
+                .short("g")
+                .long("group")
+                .required(true)
+                .takes_value(true)
+                .value_name("GROUP_NAME"),
+        )
+        .arg(
+            Arg::with_name("STREAM_NAME")
+                .help("The name of log stream")
+                .short("s")
+                .long("stream")
+                .required(true)
+                .takes_value(true)
+                .value_name("STREAM_NAME"),
+        )
+        .arg(
+            Arg::with_name("CREATE")
+                .help("Create the log group and the log stream if they do not exist.")
+                .long("create"),
+        )
+        .arg(
+            Arg::with_name("TIME_FORMAT")
+                .help("The strftime format of the timestamps. Times without an offset are treated as JST.")
+                .long("time-format")
+                .takes_value(true)
+                .default_value("%Y-%m-%d %H:%M:%S")
+                .value_name("FORMAT"),
+        )
+        .arg(
+            Arg::with_name("TIME_REGEX")
+                .help("The regular expression to find the timestamp in a line. The group named 'time' or the first group is parsed with '--time-format'. Defaults to the beginning of the line.")
+                .long("time-regex")
+                .takes_value(true)
+                .value_name("REGEX"),
+        )
+        .arg(
+            Arg::with_name("RECORD_START")
+                .help("The regular expression matching the first line of a record. Other lines are appended to the previous record. Defaults to the lines with a timestamp.")
+                .long("record-start")
+                .takes_value(true)
+                .value_name("REGEX"),
+        )
+        .arg(
+            Arg::with_name("FILE")
+                .help("The files to import")
+                .required(true)
+                .multiple(true)
+                .index(1)
+                .value_name("FILE"),
+        )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// RecordParser
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// タイムスタンプをUTCに変換する。オフセットを含まない書式はJSTとして扱う。
+fn parse_timestamp(text: &str, format: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_str(text, format)
+        .map(|dt| dt.with_timezone(&Utc))
+        .or_else(|_| {
+            TZ_ASIA_TOKYO
+                .datetime_from_str(text, format)
+                .map(|dt| dt.with_timezone(&Utc))
+        })
+        .ok()
+}
+
+/// 行の先頭から `count` 個の空白区切りの語を、間の空白も含めてそのまま取り出す
+fn leading_words(line: &str, count: usize) -> Option<&str> {
+    let mut words = 0;
+    let mut in_word = false;
+    for (i, c) in line.char_indices() {
+        if c.is_whitespace() {
+            if in_word {
+                words += 1;
+                if words == count {
+                    return Some(&line[..i]);
+                }
+            }
+            in_word = false;
+        } else {
+            in_word = true;
+        }
+    }
+
+    if in_word && words + 1 == count {
+        Some(line)
+    } else {
+        None
+    }
+}
+
+/// 行からタイムスタンプを読み取り、複数行にわたるレコードの区切りを判定する
+pub struct RecordParser {
+    time_format: String,
+    time_regex: Option<Regex>,
+    record_start: Option<Regex>,
+    /// `--time-regex` がない場合に行頭から取り出す語の数
+    format_words: usize,
+}
+
+impl RecordParser {
+    pub fn new(
+        time_format: &str,
+        time_regex: Option<&str>,
+        record_start: Option<&str>,
+    ) -> Result<Self, errors::Error> {
+        Ok(RecordParser {
+            time_format: time_format.to_string(),
+            time_regex: time_regex
+                .map(|pattern| filter::build_regex(pattern, false))
+                .transpose()?,
+            record_start: record_start
+                .map(|pattern| filter::build_regex(pattern, false))
+                .transpose()?,
+            format_words: time_format.split_whitespace().count().max(1),
+        })
+    }
+
+    fn timestamp_text<'a>(&self, line: &'a str) -> Option<&'a str> {
+        match self.time_regex {
+            Some(ref re) => {
+                let captures = re.captures(line)?;
+                captures
+                    .name("time")
+                    .or_else(|| captures.get(1))
+                    .or_else(|| captures.get(0))
+                    .map(|m| m.as_str())
+            }
+            None => leading_words(line, self.format_words),
+        }
+    }
+
+    pub fn timestamp(&self, line: &str) -> Option<DateTime<Utc>> {
+        parse_timestamp(self.timestamp_text(line)?, self.time_format.as_str())
+    }
+
+    pub fn is_record_start(&self, line: &str) -> bool {
+        match self.record_start {
+            Some(ref re) => re.is_match(line),
+            None => self.timestamp(line).is_some(),
+        }
+    }
+}
+
+/// 1つのファイルから読み取ったイベント
+struct FileRecords {
+    events: Vec<InputLogEvent>,
+    /// タイムスタンプを読み取れずに取り込まなかったレコード・行の数
+    skipped: u64,
+}
+
+/// 読み取り途中のレコード (開始行番号, タイムスタンプ, メッセージ)
+type Record = (usize, Option<DateTime<Utc>>, String);
+
+fn finish_record(path: &Path, record: Option<Record>, records: &mut FileRecords) {
+    match record {
+        Some((_, Some(timestamp), message)) => records.events.push(InputLogEvent {
+            message,
+            timestamp: timestamp.timestamp_millis(),
+        }),
+        Some((number, None, _)) => {
+            warn!("{}:{}: cannot parse the timestamp", path.display(), number);
+            records.skipped += 1;
+        }
+        None => {}
+    }
+}
+
+fn read_records(path: &Path, parser: &RecordParser) -> Result<FileRecords, errors::Error> {
+    let mut records = FileRecords {
+        events: Vec::new(),
+        skipped: 0,
+    };
+    let mut current: Option<Record> = None;
+
+    let mut input = reader::open_file(path)?;
+    let mut buffer = Vec::new();
+    let mut number = 0;
+    loop {
+        buffer.clear();
+        if input.read_until(b'\n', &mut buffer)? == 0 {
+            break;
+        }
+        number += 1;
+        let line = String::from_utf8_lossy(&buffer);
+        let line = line.trim_end_matches(&['\n', '\r'][..]);
+
+        if parser.is_record_start(line) {
+            finish_record(path, current.take(), &mut records);
+            current = Some((number, parser.timestamp(line), line.to_string()));
+        } else if let Some((_, _, ref mut message)) = current {
+            message.push('\n');
+            message.push_str(line);
+        } else if !line.is_empty() {
+            // 最初のレコードより前の行はどのレコードにも属さない
+            warn!(
+                "{}:{}: no record to append the line to",
+                path.display(),
+                number
+            );
+            records.skipped += 1;
+        }
+    }
+    finish_record(path, current.take(), &mut records);
+
+    Ok(records)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// run
+//
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("parse import options");
+    let options = ImportOptions::from(matches);
+    let target = cmd::single_target(targets)?;
+    let parser = RecordParser::new(
+        options.time_format,
+        options.time_regex,
+        options.record_start,
+    )?;
+
+    info!("read files");
+    let mut events = Vec::new();
+    let mut skipped = 0;
+    for path in options.files.iter() {
+        debug!("read {}", path);
+        let records = read_records(Path::new(path), &parser)?;
+        events.extend(records.events);
+        skipped += records.skipped;
+    }
+
+    // ファイルをまたいでも時系列順に送信する
+    events.sort_by_key(|event| event.timestamp);
+
+    info!("create writer");
+    let mut writer = LogEventsWriter::new(target.client, options.group_name, options.stream_name);
+    if options.create {
+        writer.create_if_missing()?;
+    }
+
+    info!("put events");
+    let progress = atty::is(atty::Stream::Stderr);
+    let total = events.len();
+    for (i, event) in events.into_iter().enumerate() {
+        writer.write(event)?;
+        if progress && (i + 1) % 10_000 == 0 {
+            eprint!("\r{}/{} events sent", i + 1, total);
+        }
+    }
+    writer.flush()?;
+    if progress && total >= 10_000 {
+        eprintln!();
+    }
+
+    let rejected = writer.rejected();
+    println!(
+        "Imported {} events from {} files to {}",
+        writer.written().saturating_sub(rejected.total()),
+        options.files.len(),
+        options.group_name
+    );
+    if rejected.too_old > 0 {
+        println!("  {} events were rejected as too old", rejected.too_old);
+    }
+    if rejected.too_new > 0 {
+        println!("  {} events were rejected as too new", rejected.too_new);
+    }
+    if rejected.expired > 0 {
+        println!(
+            "  {} events were rejected by the retention policy",
+            rejected.expired
+        );
+    }
+    if skipped > 0 {
+        println!("  {} records were skipped without a timestamp", skipped);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn write_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("import-{}-{}.log", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn jst(text: &str) -> i64 {
+        TZ_ASIA_TOKYO
+            .datetime_from_str(text, "%Y-%m-%d %H:%M:%S")
+            .unwrap()
+            .timestamp_millis()
+    }
+
+    fn read(name: &str, text: &str, parser: &RecordParser) -> (Vec<(i64, String)>, u64) {
+        let path = write_file(name, text);
+        let records = read_records(&path, parser).unwrap();
+        fs::remove_file(&path).unwrap();
+        let events = records
+            .events
+            .into_iter()
+            .map(|event| (event.timestamp, event.message))
+            .collect();
+        (events, records.skipped)
+    }
+
+    #[test]
+    fn leading_words_keeps_the_spaces_between_words() {
+        let cases = [
+            (
+                "2019-01-02 03:04:05 message",
+                2,
+                Some("2019-01-02 03:04:05"),
+            ),
+            (
+                "2019-01-02  03:04:05 message",
+                2,
+                Some("2019-01-02  03:04:05"),
+            ),
+            ("2019-01-02 03:04:05", 2, Some("2019-01-02 03:04:05")),
+            ("2019-01-02", 2, None),
+            ("", 1, None),
+        ];
+        for (line, count, expected) in cases.iter() {
+            assert_eq!(leading_words(line, *count), *expected, "line: {:?}", line);
+        }
+    }
+
+    #[test]
+    fn timestamps_without_offset_are_jst() {
+        let parser = RecordParser::new("%Y-%m-%d %H:%M:%S", None, None).unwrap();
+        assert_eq!(
+            parser.timestamp("2019-01-02 12:00:00 started"),
+            Some(Utc.ymd(2019, 1, 2).and_hms(3, 0, 0))
+        );
+
+        let parser = RecordParser::new("%Y-%m-%dT%H:%M:%S%z", None, None).unwrap();
+        assert_eq!(
+            parser.timestamp("2019-01-02T12:00:00+0000 started"),
+            Some(Utc.ymd(2019, 1, 2).and_hms(12, 0, 0))
+        );
+    }
+
+    #[test]
+    fn timestamps_found_by_regex() {
+        let parser =
+            RecordParser::new("%d/%b/%Y:%H:%M:%S %z", Some(r"\[(?P<time>[^\]]+)\]"), None).unwrap();
+        assert_eq!(
+            parser.timestamp(r#"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET / HTTP/1.0""#),
+            Some(Utc.ymd(2000, 10, 10).and_hms(20, 55, 36))
+        );
+        assert_eq!(parser.timestamp("no timestamp"), None);
+    }
+
+    #[test]
+    fn continuation_lines_are_appended_to_the_record() {
+        let parser = RecordParser::new("%Y-%m-%d %H:%M:%S", None, None).unwrap();
+        let text = "orphan line\n\
+                    2019-01-02 03:04:05 first\n\
+                    \tat Foo.bar\n\
+                    \n\
+                    2019-01-02 03:04:06 second\r\n";
+
+        let (events, skipped) = read("continuation", text, &parser);
+
+        assert_eq!(
+            events,
+            vec![
+                (
+                    jst("2019-01-02 03:04:05"),
+                    "2019-01-02 03:04:05 first\n\tat Foo.bar\n".to_string()
+                ),
+                (
+                    jst("2019-01-02 03:04:06"),
+                    "2019-01-02 03:04:06 second".to_string()
+                ),
+            ]
+        );
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn records_start_at_the_given_pattern() {
+        let parser =
+            RecordParser::new("%Y-%m-%d %H:%M:%S", None, Some(r"^\d{4}-\d{2}-\d{2} ")).unwrap();
+        let text = "2019-01-02 03:04:05 first\n\
+                    2019-01-02 03:04:05 is not 2019-01-02 03:04:06\n\
+                    2019-13-01 00:00:00 invalid\n\
+                    continued\n";
+
+        let (events, skipped) = read("record-start", text, &parser);
+
+        let messages: Vec<&str> = events.iter().map(|(_, m)| m.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "2019-01-02 03:04:05 first",
+                "2019-01-02 03:04:05 is not 2019-01-02 03:04:06"
+            ]
+        );
+        // タイムスタンプを読み取れないレコードは続きの行も含めて1件として数える
+        assert_eq!(skipped, 1);
+    }
+}
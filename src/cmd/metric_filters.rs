@@ -0,0 +1,489 @@
+use std::io::BufRead;
+use std::sync::mpsc;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use failure::{format_err, ResultExt};
+use futures::prelude::*;
+use log::info;
+use rusoto_logs::{
+    CloudWatchLogs, DeleteMetricFilterError, DeleteMetricFilterRequest, DescribeMetricFiltersError,
+    DescribeMetricFiltersRequest, MetricFilter, MetricFilterMatchRecord, MetricTransformation,
+    PutMetricFilterError, PutMetricFilterRequest, TestMetricFilterError, TestMetricFilterRequest,
+};
+
+use crate::cmd::get::event::LogEventsRequest;
+use crate::cmd::get::{self, reader, stream};
+use crate::cmd::group::{group_arg, yes_arg};
+use crate::cmd::{self, Target};
+use crate::errors;
+
+/// TestMetricFilterに一度に渡せるメッセージの数
+const MAX_TEST_MESSAGES: usize = 50;
+
+fn validate_number(s: String) -> Result<(), String> {
+    s.parse::<f64>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a number", s))
+}
+
+fn filter_name_arg() -> Arg<'static, 'static> {
+    Arg::with_name("FILTER_NAME")
+        .help("The name of the metric filter")
+        .short("n")
+        .long("name")
+        .required(true)
+        .takes_value(true)
+        .value_name("FILTER_NAME")
+}
+
+fn filter_pattern_arg() -> Arg<'static, 'static> {
+    Arg::with_name("FILTER_EXPRESSION")
+        .help("The filter pattern")
+        .short("f")
+        .long("filter-pattern")
+        .required(true)
+        .takes_value(true)
+        .value_name("FILTER_EXPRESSION")
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Manage and test metric filters")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the metric filters")
+                .arg(group_arg().required(false))
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("List only the metric filters whose name starts with PREFIX. Requires '--group'.")
+                        .long("prefix")
+                        .takes_value(true)
+                        .requires("GROUP_NAME")
+                        .value_name("PREFIX"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Create or update a metric filter")
+                .arg(group_arg())
+                .arg(filter_name_arg())
+                .arg(filter_pattern_arg())
+                .arg(
+                    Arg::with_name("NAMESPACE")
+                        .help("The namespace of the CloudWatch metric")
+                        .long("namespace")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("NAMESPACE"),
+                )
+                .arg(
+                    Arg::with_name("METRIC_NAME")
+                        .help("The name of the CloudWatch metric")
+                        .long("metric-name")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("METRIC_NAME"),
+                )
+                .arg(
+                    Arg::with_name("METRIC_VALUE")
+                        .help("The value to publish when an event matches, such as '1' or '$.latency'")
+                        .long("metric-value")
+                        .takes_value(true)
+                        .default_value("1")
+                        .value_name("VALUE"),
+                )
+                .arg(
+                    Arg::with_name("DEFAULT_VALUE")
+                        .help("The value to publish when an event does not match")
+                        .long("default-value")
+                        .takes_value(true)
+                        .validator(validate_number)
+                        .value_name("NUMBER"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Delete a metric filter")
+                .arg(group_arg())
+                .arg(filter_name_arg())
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .about("Test a filter pattern against sample lines and show the extracted values")
+                .arg(filter_pattern_arg())
+                .arg(
+                    Arg::with_name("METRIC_VALUE")
+                        .help("Also show the metric value, such as '$.latency', each matched line would publish")
+                        .long("metric-value")
+                        .takes_value(true)
+                        .value_name("VALUE"),
+                )
+                .arg(
+                    Arg::with_name("SAMPLE_FILE")
+                        .help("The file (plain or gzip-compressed) whose lines are tested")
+                        .long("sample-file")
+                        .takes_value(true)
+                        .required_unless("GROUP_NAME")
+                        .conflicts_with("GROUP_NAME")
+                        .value_name("PATH"),
+                )
+                .arg(
+                    group_arg()
+                        .required(false)
+                        .help("The name of the log group to fetch the sample events from")
+                        .requires("START_TIME"),
+                )
+                .arg(
+                    Arg::with_name("STREAM_NAME")
+                        .help("The name of log stream to fetch the sample events from. Can be given multiple times.")
+                        .short("s")
+                        .long("stream")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .requires("GROUP_NAME")
+                        .value_name("STREAM_NAME"),
+                )
+                .arg(
+                    Arg::with_name("START_TIME")
+                        .help("The start of the time range to fetch the sample events")
+                        .long("start-time")
+                        .takes_value(true)
+                        .requires("GROUP_NAME")
+                        .value_name("TIME")
+                        .validator(get::validate_jst_text),
+                )
+                .arg(
+                    Arg::with_name("END_TIME")
+                        .help("The end of the time range to fetch the sample events")
+                        .long("end-time")
+                        .takes_value(true)
+                        .requires("GROUP_NAME")
+                        .value_name("TIME")
+                        .validator(get::validate_jst_text),
+                )
+                .arg(
+                    Arg::with_name("COUNT")
+                        .help("The maximum number of sample events to fetch")
+                        .long("count")
+                        .takes_value(true)
+                        .default_value("100")
+                        .validator(get::validate_positive_count)
+                        .value_name("NUM"),
+                )
+                .arg(
+                    Arg::with_name("MATCHES_ONLY")
+                        .help("Show only the lines matching the pattern.")
+                        .long("matches-only"),
+                ),
+        )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Actions
+//
+////////////////////////////////////////////////////////////////////////////////
+
+fn describe_metric_filters(
+    target: &Target,
+    group_name: Option<&str>,
+    prefix: Option<&str>,
+) -> Result<Vec<MetricFilter>, errors::Error> {
+    let mut filters = Vec::new();
+    let mut next_token = None;
+    loop {
+        let res = target
+            .client
+            .describe_metric_filters(DescribeMetricFiltersRequest {
+                filter_name_prefix: prefix.map(|s| s.to_string()),
+                log_group_name: group_name.map(|s| s.to_string()),
+                next_token,
+                ..Default::default()
+            })
+            .sync()?;
+        filters.extend(res.metric_filters.unwrap_or_default());
+
+        next_token = res.next_token;
+        if next_token.is_none() {
+            return Ok(filters);
+        }
+    }
+}
+
+fn format_transformation(transformation: &MetricTransformation) -> String {
+    let mut text = format!(
+        "{}/{} = {}",
+        transformation.metric_namespace, transformation.metric_name, transformation.metric_value
+    );
+    if let Some(value) = transformation.default_value {
+        text.push_str(format!(" (default {})", value).as_str());
+    }
+    text
+}
+
+fn list(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME");
+    let filters = describe_metric_filters(target, group_name, matches.value_of("PREFIX"))?;
+    for filter in filters.iter() {
+        let transformations = filter
+            .metric_transformations
+            .as_ref()
+            .map(|transformations| {
+                transformations
+                    .iter()
+                    .map(format_transformation)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .unwrap_or_default();
+
+        // ロググループを指定しない場合はすべてのロググループの分が返るので名前も表示する
+        let name = match group_name {
+            Some(_) => filter.filter_name.clone().unwrap_or_default(),
+            None => format!(
+                "{}\t{}",
+                filter.log_group_name.as_deref().unwrap_or(""),
+                filter.filter_name.as_deref().unwrap_or("")
+            ),
+        };
+        println!(
+            "{}\t{}\t{}",
+            name,
+            filter.filter_pattern.as_deref().unwrap_or(""),
+            transformations
+        );
+    }
+    Ok(())
+}
+
+fn put(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME").unwrap();
+    let filter_name = matches.value_of("FILTER_NAME").unwrap();
+    let transformation = MetricTransformation {
+        metric_namespace: matches.value_of("NAMESPACE").unwrap().to_string(),
+        metric_name: matches.value_of("METRIC_NAME").unwrap().to_string(),
+        metric_value: matches.value_of("METRIC_VALUE").unwrap().to_string(),
+        default_value: matches
+            .value_of("DEFAULT_VALUE")
+            .map(|s| s.parse().unwrap()),
+    };
+    let description = format_transformation(&transformation);
+
+    target
+        .client
+        .put_metric_filter(PutMetricFilterRequest {
+            filter_name: filter_name.to_string(),
+            filter_pattern: matches.value_of("FILTER_EXPRESSION").unwrap().to_string(),
+            log_group_name: group_name.to_string(),
+            metric_transformations: vec![transformation],
+        })
+        .sync()?;
+    println!(
+        "Put {} on {} in {}: {}",
+        filter_name,
+        group_name,
+        target.label(),
+        description
+    );
+    Ok(())
+}
+
+fn delete(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME").unwrap();
+    let filter_name = matches.value_of("FILTER_NAME").unwrap();
+    let message = format!(
+        "Delete {} on {} in {}?",
+        filter_name,
+        group_name,
+        target.label()
+    );
+    if !cmd::confirm(message.as_str(), matches.is_present("YES"))? {
+        return Ok(());
+    }
+
+    target
+        .client
+        .delete_metric_filter(DeleteMetricFilterRequest {
+            filter_name: filter_name.to_string(),
+            log_group_name: group_name.to_string(),
+        })
+        .sync()?;
+    println!(
+        "Deleted {} on {} in {}",
+        filter_name,
+        group_name,
+        target.label()
+    );
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// test
+//
+////////////////////////////////////////////////////////////////////////////////
+
+fn read_sample_file(path: &str) -> Result<Vec<String>, errors::Error> {
+    let mut lines = Vec::new();
+    for line in reader::open_file(path)?.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+fn fetch_sample_events(
+    target: &Target,
+    matches: &ArgMatches,
+) -> Result<Vec<String>, errors::Error> {
+    let count = get::matches_count(matches, "COUNT").unwrap();
+    let reader = reader::FilterLogEventsReader {
+        client: target.client.clone(),
+        group_name: matches.value_of("GROUP_NAME").unwrap().to_string(),
+        stream_names: matches
+            .values_of("STREAM_NAME")
+            .map(|names| names.map(|s| s.to_string()).collect()),
+        filter_expression: None,
+        request: LogEventsRequest {
+            start_time: matches.value_of("START_TIME").map(get::from_jst_text),
+            end_time: matches.value_of("END_TIME").map(get::from_jst_text),
+            limit: Some(count.min(reader::MAX_LIMIT) as i64),
+        },
+    };
+    let stream =
+        stream::limit_log_events_stream(stream::create_log_events_stream(Box::new(reader)), count);
+
+    let (sender, receiver) = mpsc::channel();
+    let f = stream
+        .fold(Vec::new(), |mut messages, res| {
+            messages.extend(res.events.into_iter().map(|event| event.message));
+            Ok::<_, errors::Error>(messages)
+        })
+        .map(move |messages| {
+            sender.send(messages).unwrap();
+        });
+    cmd::run_future(Box::new(f))?;
+
+    Ok(receiver.recv().unwrap_or_default())
+}
+
+/// TestMetricFilterで各行を試し、行ごとの一致結果を返す
+fn test_messages(
+    target: &Target,
+    filter_pattern: &str,
+    messages: &[String],
+) -> Result<Vec<Option<MetricFilterMatchRecord>>, errors::Error> {
+    let mut results = vec![None; messages.len()];
+    for (i, chunk) in messages.chunks(MAX_TEST_MESSAGES).enumerate() {
+        let res = target
+            .client
+            .test_metric_filter(TestMetricFilterRequest {
+                filter_pattern: filter_pattern.to_string(),
+                log_event_messages: chunk.to_vec(),
+            })
+            .sync()?;
+
+        // eventNumber はリクエストごとに1から数えられる
+        for record in res.matches.unwrap_or_default() {
+            let number = record.event_number.unwrap_or(0) as usize;
+            if number >= 1 && number <= chunk.len() {
+                results[i * MAX_TEST_MESSAGES + number - 1] = Some(record);
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// 一致した行から発行されるメトリクスの値を求める
+fn metric_value(metric_value: &str, record: &MetricFilterMatchRecord) -> String {
+    if !metric_value.starts_with('$') {
+        return metric_value.to_string();
+    }
+    record
+        .extracted_values
+        .as_ref()
+        .and_then(|values| values.get(metric_value))
+        .cloned()
+        .unwrap_or_else(|| format!("({} not extracted)", metric_value))
+}
+
+fn test(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let filter_pattern = matches.value_of("FILTER_EXPRESSION").unwrap();
+    let value_expression = matches.value_of("METRIC_VALUE");
+    let matches_only = matches.is_present("MATCHES_ONLY");
+
+    info!("read sample lines");
+    let messages = match matches.value_of("SAMPLE_FILE") {
+        Some(path) => read_sample_file(path)?,
+        None => fetch_sample_events(target, matches)?,
+    };
+    if messages.is_empty() {
+        println!("No sample lines to test");
+        return Ok(());
+    }
+
+    info!("test metric filter");
+    let results = test_messages(target, filter_pattern, &messages)?;
+    let mut matched = 0;
+    for (message, result) in messages.iter().zip(results.iter()) {
+        let record = match result {
+            Some(record) => record,
+            None => {
+                if !matches_only {
+                    println!("- {}", message);
+                }
+                continue;
+            }
+        };
+
+        matched += 1;
+        println!("+ {}", message);
+        let mut values: Vec<(&String, &String)> = record
+            .extracted_values
+            .as_ref()
+            .map(|values| values.iter().collect())
+            .unwrap_or_default();
+        values.sort();
+        for (key, value) in values {
+            println!("    {} = {}", key, value);
+        }
+        if let Some(expression) = value_expression {
+            println!("    metric value: {}", metric_value(expression, record));
+        }
+    }
+    println!("{} of {} lines matched", matched, messages.len());
+    Ok(())
+}
+
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("parse metric-filters options");
+    let target = cmd::single_target(targets)?;
+
+    info!("invoke metric-filters commands");
+    match matches.subcommand() {
+        ("list", Some(m)) => list(&target, m),
+        ("put", Some(m)) => put(&target, m),
+        ("delete", Some(m)) => delete(&target, m),
+        ("test", Some(m)) => test(&target, m),
+        (name, _) => Err(format_err!("Unknown metric-filters command: '{}'", name))
+            .context(errors::ErrorKind::NoSubCommand)?,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Errors
+//
+////////////////////////////////////////////////////////////////////////////////
+
+errors::impl_from_rusoto_error!(
+    DeleteMetricFilterError,
+    DescribeMetricFiltersError,
+    PutMetricFilterError,
+    TestMetricFilterError,
+);
@@ -0,0 +1,143 @@
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{debug, info, warn};
+use rusoto_logs::InputLogEvent;
+
+use crate::cmd::{self, get, Target};
+use crate::errors;
+
+pub mod writer;
+
+use self::writer::LogEventsWriter;
+
+pub struct PutOptions<'a> {
+    group_name: &'a str,
+    stream_name: &'a str,
+    create: bool,
+    flush_interval: Duration,
+}
+
+impl<'a> From<&'a ArgMatches<'a>> for PutOptions<'a> {
+    fn from(matches: &'a ArgMatches<'a>) -> Self {
+        PutOptions {
+            group_name: matches.value_of("GROUP_NAME").unwrap(),
+            stream_name: matches.value_of("STREAM_NAME").unwrap(),
+            create: matches.is_present("CREATE"),
+            flush_interval: Duration::from_secs(
+                get::matches_count(matches, "FLUSH_INTERVAL").unwrap() as u64,
+            ),
+        }
+    }
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Send the lines of the standard input to a log stream")
+        .arg(
+            Arg::with_name("GROUP_NAME")
+                .help("The name of the log group")
+                .short("g")
+                .long("group")
+                .required(true)
+                .takes_value(true)
+                .value_name("GROUP_NAME"),
+        )
+        .arg(
+            Arg::with_name("STREAM_NAME")
+                .help("The name of log stream")
+                .short("s")
+                .long("stream")
+                .required(true)
+                .takes_value(true)
+                .value_name("STREAM_NAME"),
+        )
+        .arg(
+            Arg::with_name("CREATE")
+                .help("Create the log group and the log stream if they do not exist.")
+                .long("create"),
+        )
+        .arg(
+            Arg::with_name("FLUSH_INTERVAL")
+                .help("Send the buffered lines at least every SECONDS.")
+                .long("flush-interval")
+                .takes_value(true)
+                .default_value("5")
+                .validator(get::validate_positive_count)
+                .value_name("SECONDS"),
+        )
+}
+
+/// 現在時刻をタイムスタンプとした1行分のイベントを作る
+pub fn line_event(line: String) -> InputLogEvent {
+    InputLogEvent {
+        message: line.trim_end_matches(&['\n', '\r'][..]).to_string(),
+        timestamp: Utc::now().timestamp_millis(),
+    }
+}
+
+/// 送信側が閉じるまでイベントを受け取って書き込む
+///
+/// 入力が途切れても `flush_interval` ごとにためたイベントを送信する。
+pub fn forward_events(
+    receiver: Receiver<InputLogEvent>,
+    writer: &mut LogEventsWriter,
+    flush_interval: Duration,
+) -> Result<(), errors::Error> {
+    let mut last_flush = Instant::now();
+    loop {
+        let timeout = flush_interval
+            .checked_sub(last_flush.elapsed())
+            .unwrap_or_default();
+        match receiver.recv_timeout(timeout) {
+            Ok(event) => writer.write(event)?,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if last_flush.elapsed() >= flush_interval {
+            writer.flush()?;
+            last_flush = Instant::now();
+        }
+    }
+
+    writer.flush()
+}
+
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("parse put options");
+    let options = PutOptions::from(matches);
+    let target = cmd::single_target(targets)?;
+
+    info!("create writer");
+    let mut writer = LogEventsWriter::new(target.client, options.group_name, options.stream_name);
+    if options.create {
+        writer.create_if_missing()?;
+    }
+
+    // 標準入力の読み込みで送信が止まらないよう別スレッドで読む
+    let (sender, receiver) = mpsc::channel();
+    let input = thread::spawn(move || -> io::Result<()> {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            if sender.send(line_event(line?)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    info!("forward events");
+    forward_events(receiver, &mut writer, options.flush_interval)?;
+    input.join().expect("failed to join the input thread")?;
+
+    debug!("put {} events", writer.written());
+    if writer.skipped() > 0 {
+        warn!("{} empty lines were skipped", writer.skipped());
+    }
+    Ok(())
+}
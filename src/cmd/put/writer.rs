@@ -0,0 +1,476 @@
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use failure::{format_err, ResultExt};
+use log::{debug, warn};
+use rusoto_logs::{
+    CloudWatchLogs, CloudWatchLogsClient, CreateLogGroupError, CreateLogGroupRequest,
+    CreateLogStreamError, CreateLogStreamRequest, InputLogEvent, PutLogEventsError,
+    PutLogEventsRequest, RejectedLogEventsInfo,
+};
+
+use crate::errors;
+
+/// PutLogEventsの1回の呼び出しで送れる最大バイト数 (イベントごとのオーバーヘッドを含む)
+pub const MAX_BATCH_BYTES: usize = 1_048_576;
+
+/// PutLogEventsの1回の呼び出しで送れる最大件数
+pub const MAX_BATCH_EVENTS: usize = 10_000;
+
+/// 1回の呼び出しに含められるイベントの時間幅
+pub const MAX_BATCH_SPAN_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+/// メッセージのバイト数に加えて1イベントごとに数えられるバイト数
+const EVENT_OVERHEAD: usize = 26;
+
+/// 1イベントの最大バイト数 (オーバーヘッドを含む)
+const MAX_EVENT_BYTES: usize = 256 * 1024;
+
+/// シーケンストークンの不一致で再送する回数
+const MAX_RETRIES: usize = 5;
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Helper functions
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// 大きすぎるメッセージを文字の途中で切らないように切り詰める
+fn truncate_message(mut message: String) -> String {
+    let max = MAX_EVENT_BYTES - EVENT_OVERHEAD;
+    if message.len() > max {
+        let mut end = max;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    message
+}
+
+/// `InvalidSequenceTokenException` などのメッセージから次に使うべきトークンを取り出す
+///
+/// 例: `The given sequenceToken is invalid. The next expected sequenceToken is: 4956...`
+/// 新しいストリームの場合は `null` が返されるので `None` とする。
+fn expected_sequence_token(message: &str) -> Option<String> {
+    message
+        .rsplit(':')
+        .next()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty() && *s != "null")
+        .map(|s| s.to_string())
+}
+
+/// PutLogEventsで受け付けられなかったイベントの件数
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RejectedEvents {
+    /// 14日より古い
+    pub too_old: u64,
+    /// 2時間以上先
+    pub too_new: u64,
+    /// 保持期間を過ぎている
+    pub expired: u64,
+    /// 受け付けられなかったイベントの合計 (理由が重なるイベントは1件と数える)
+    total: u64,
+}
+
+impl RejectedEvents {
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// 時系列順に並べて送信した `events` に対する応答から件数を数え、対象の期間を警告する
+    fn add(&mut self, info: &RejectedLogEventsInfo, events: &[InputLogEvent]) {
+        let range = |start: usize, end: usize| {
+            let end = end.min(events.len());
+            if start >= end {
+                return (0, String::new());
+            }
+            let from = Utc.timestamp_millis(events[start].timestamp);
+            let to = Utc.timestamp_millis(events[end - 1].timestamp);
+            (
+                (end - start) as u64,
+                format!("{} - {}", from.to_rfc3339(), to.to_rfc3339()),
+            )
+        };
+
+        // 終了位置のインデックスはその位置を含まない
+        let mut too_old = 0;
+        let mut too_new = 0;
+        let mut expired = 0;
+        if let Some(index) = info.too_old_log_event_end_index {
+            let (count, period) = range(0, index as usize);
+            warn!("{} events were rejected as too old: {}", count, period);
+            too_old = count;
+        }
+        if let Some(index) = info.too_new_log_event_start_index {
+            let (count, period) = range(index as usize, events.len());
+            warn!("{} events were rejected as too new: {}", count, period);
+            too_new = count;
+        }
+        if let Some(index) = info.expired_log_event_end_index {
+            let (count, period) = range(0, index as usize);
+            warn!(
+                "{} events were rejected by the retention policy: {}",
+                count, period
+            );
+            expired = count;
+        }
+
+        self.too_old += too_old;
+        self.too_new += too_new;
+        self.expired += expired;
+        // 古すぎるものと保持期間切れのものはどちらもバッチの先頭からの範囲なので、バッチごとに重なりを除く
+        self.total += too_old.max(expired) + too_new;
+    }
+}
+
+/// ロググループがなければ作成する
+pub fn create_group_if_missing(
+    client: &CloudWatchLogsClient,
+    group_name: &str,
+) -> Result<(), errors::Error> {
+    let created = client
+        .create_log_group(CreateLogGroupRequest {
+            log_group_name: group_name.to_string(),
+            ..Default::default()
+        })
+        .sync();
+    match created {
+        Ok(_) => debug!("created log group {}", group_name),
+        Err(CreateLogGroupError::ResourceAlreadyExists(_)) => {}
+        Err(e) => return Err(errors::Error::from(e)),
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// LogEventsWriter
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// イベントをためておき、PutLogEventsの制限内に収まる単位でまとめて送信する
+pub struct LogEventsWriter {
+    client: Arc<CloudWatchLogsClient>,
+    group_name: String,
+    stream_name: String,
+    sequence_token: Option<String>,
+    batch: Vec<InputLogEvent>,
+    batch_bytes: usize,
+    /// ためているイベントの最も古いタイムスタンプと最も新しいタイムスタンプ
+    batch_span: Option<(i64, i64)>,
+    written: u64,
+    skipped: u64,
+    rejected: RejectedEvents,
+}
+
+impl LogEventsWriter {
+    pub fn new(client: Arc<CloudWatchLogsClient>, group_name: &str, stream_name: &str) -> Self {
+        LogEventsWriter {
+            client,
+            group_name: group_name.to_string(),
+            stream_name: stream_name.to_string(),
+            sequence_token: None,
+            batch: Vec::new(),
+            batch_bytes: 0,
+            batch_span: None,
+            written: 0,
+            skipped: 0,
+            rejected: RejectedEvents::default(),
+        }
+    }
+
+    /// ロググループとログストリームがなければ作成する
+    pub fn create_if_missing(&self) -> Result<(), errors::Error> {
+        create_group_if_missing(&self.client, self.group_name.as_str())?;
+        self.create_stream_if_missing()
+    }
+
+    /// ログストリームがなければ作成する。ロググループは既にあるものとする。
+    pub fn create_stream_if_missing(&self) -> Result<(), errors::Error> {
+        let created_stream = self
+            .client
+            .create_log_stream(CreateLogStreamRequest {
+                log_group_name: self.group_name.clone(),
+                log_stream_name: self.stream_name.clone(),
+            })
+            .sync();
+        match created_stream {
+            Ok(_) => debug!("created log stream {}", self.stream_name),
+            Err(CreateLogStreamError::ResourceAlreadyExists(_)) => {}
+            Err(e) => return Err(errors::Error::from(e)),
+        }
+        Ok(())
+    }
+
+    /// 送信済みのイベント数 (受け付けられなかったものを含む)
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// メッセージが空のため送信しなかったイベントの件数
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// 受け付けられなかったイベントの件数
+    pub fn rejected(&self) -> RejectedEvents {
+        self.rejected
+    }
+
+    fn fits(&self, event: &InputLogEvent, size: usize) -> bool {
+        if self.batch.len() + 1 > MAX_BATCH_EVENTS || self.batch_bytes + size > MAX_BATCH_BYTES {
+            return false;
+        }
+
+        match self.batch_span {
+            Some((oldest, newest)) => {
+                newest.max(event.timestamp) - oldest.min(event.timestamp) <= MAX_BATCH_SPAN_MILLIS
+            }
+            None => true,
+        }
+    }
+
+    /// イベントを追加する。制限を超える場合はそれまでのイベントを先に送信する。
+    pub fn write(&mut self, event: InputLogEvent) -> Result<(), errors::Error> {
+        // 空のメッセージは受け付けられない
+        if event.message.is_empty() {
+            debug!("skip an empty event");
+            self.skipped += 1;
+            return Ok(());
+        }
+
+        let event = InputLogEvent {
+            message: truncate_message(event.message),
+            ..event
+        };
+        let size = event.message.len() + EVENT_OVERHEAD;
+        if !self.fits(&event, size) {
+            self.flush()?;
+        }
+
+        self.batch_span = Some(match self.batch_span {
+            Some((oldest, newest)) => (oldest.min(event.timestamp), newest.max(event.timestamp)),
+            None => (event.timestamp, event.timestamp),
+        });
+        self.batch.push(event);
+        self.batch_bytes += size;
+        Ok(())
+    }
+
+    /// ためているイベントを時系列順に並べて送信する
+    pub fn flush(&mut self) -> Result<(), errors::Error> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut events = Vec::new();
+        std::mem::swap(&mut events, &mut self.batch);
+        self.batch_bytes = 0;
+        self.batch_span = None;
+        events.sort_by_key(|event| event.timestamp);
+        let count = events.len();
+
+        for _ in 0..MAX_RETRIES {
+            let result = self
+                .client
+                .put_log_events(PutLogEventsRequest {
+                    log_events: events.clone(),
+                    log_group_name: self.group_name.clone(),
+                    log_stream_name: self.stream_name.clone(),
+                    sequence_token: self.sequence_token.clone(),
+                })
+                .sync();
+
+            match result {
+                Ok(res) => {
+                    debug!("put {} events", count);
+                    if let Some(info) = res.rejected_log_events_info.as_ref() {
+                        self.rejected.add(info, &events);
+                    }
+                    self.sequence_token = res.next_sequence_token;
+                    self.written += count as u64;
+                    return Ok(());
+                }
+                // 他のプロセスが同じストリームに書き込んだ場合などはトークンを更新して再送する
+                Err(PutLogEventsError::InvalidSequenceToken(message)) => {
+                    debug!("retry with the expected sequence token: {}", message);
+                    self.sequence_token = expected_sequence_token(message.as_str());
+                }
+                // 前回の送信が実は成功していた場合は送信済みとして扱う
+                Err(PutLogEventsError::DataAlreadyAccepted(message)) => {
+                    debug!("already accepted: {}", message);
+                    self.sequence_token = expected_sequence_token(message.as_str());
+                    self.written += count as u64;
+                    return Ok(());
+                }
+                Err(e) => return Err(errors::Error::from(e)),
+            }
+        }
+
+        Err(format_err!(
+            "Gave up putting {} events after {} retries of the sequence token",
+            count,
+            MAX_RETRIES
+        ))
+        .context(errors::ErrorKind::Rusoto)?
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Errors
+//
+////////////////////////////////////////////////////////////////////////////////
+
+errors::impl_from_rusoto_error!(PutLogEventsError, CreateLogGroupError, CreateLogStreamError);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::Region;
+
+    fn writer() -> LogEventsWriter {
+        let client = Arc::new(CloudWatchLogsClient::new(Region::ApNortheast1));
+        LogEventsWriter::new(client, "group", "stream")
+    }
+
+    fn event(timestamp: i64, message: &str) -> InputLogEvent {
+        InputLogEvent {
+            message: message.to_string(),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn fits_up_to_the_event_count_limit() {
+        let mut writer = writer();
+        for i in 0..MAX_BATCH_EVENTS {
+            writer.write(event(i as i64, "x")).unwrap();
+        }
+
+        assert_eq!(writer.batch.len(), MAX_BATCH_EVENTS);
+        assert!(!writer.fits(&event(0, "x"), 1 + EVENT_OVERHEAD));
+    }
+
+    #[test]
+    fn fits_up_to_the_byte_limit() {
+        let mut writer = writer();
+        let message = "x".repeat(MAX_EVENT_BYTES - EVENT_OVERHEAD);
+        for _ in 0..MAX_BATCH_BYTES / MAX_EVENT_BYTES {
+            writer.write(event(0, message.as_str())).unwrap();
+        }
+
+        assert_eq!(writer.batch_bytes, MAX_BATCH_BYTES);
+        assert!(!writer.fits(&event(0, "x"), 1 + EVENT_OVERHEAD));
+    }
+
+    #[test]
+    fn fits_within_the_24_hour_span() {
+        let mut writer = writer();
+        writer.write(event(1_000, "first")).unwrap();
+        writer
+            .write(event(1_000 + MAX_BATCH_SPAN_MILLIS, "last"))
+            .unwrap();
+        assert_eq!(
+            writer.batch_span,
+            Some((1_000, 1_000 + MAX_BATCH_SPAN_MILLIS))
+        );
+
+        // 新しい側にも古い側にも広げられない
+        assert!(writer.fits(&event(1_000 + MAX_BATCH_SPAN_MILLIS, "x"), 27));
+        assert!(!writer.fits(&event(1_001 + MAX_BATCH_SPAN_MILLIS, "x"), 27));
+        assert!(!writer.fits(&event(999, "x"), 27));
+    }
+
+    #[test]
+    fn skips_empty_messages() {
+        let mut writer = writer();
+        writer.write(event(0, "")).unwrap();
+        writer.write(event(0, "x")).unwrap();
+
+        assert_eq!(writer.skipped(), 1);
+        assert_eq!(writer.batch.len(), 1);
+    }
+
+    fn rejected_info(
+        too_old_end: Option<i64>,
+        too_new_start: Option<i64>,
+        expired_end: Option<i64>,
+    ) -> RejectedLogEventsInfo {
+        RejectedLogEventsInfo {
+            too_old_log_event_end_index: too_old_end,
+            too_new_log_event_start_index: too_new_start,
+            expired_log_event_end_index: expired_end,
+        }
+    }
+
+    #[test]
+    fn rejected_events_are_counted_per_reason() {
+        let events: Vec<InputLogEvent> = (0..10).map(|i| event(i, "x")).collect();
+        let mut rejected = RejectedEvents::default();
+
+        rejected.add(&rejected_info(Some(2), Some(8), None), &events);
+
+        assert_eq!(rejected.too_old, 2);
+        assert_eq!(rejected.too_new, 2);
+        assert_eq!(rejected.expired, 0);
+        assert_eq!(rejected.total(), 4);
+    }
+
+    #[test]
+    fn rejected_events_do_not_count_overlaps_twice() {
+        let events: Vec<InputLogEvent> = (0..10).map(|i| event(i, "x")).collect();
+        let mut rejected = RejectedEvents::default();
+
+        // 古すぎるものと保持期間切れのものはどちらもバッチの先頭から数える
+        rejected.add(&rejected_info(Some(3), None, Some(5)), &events);
+        assert_eq!((rejected.too_old, rejected.expired), (3, 5));
+        assert_eq!(rejected.total(), 5);
+
+        // バッチをまたいだ合計は各バッチの合計を足したもの
+        rejected.add(&rejected_info(Some(4), Some(9), Some(1)), &events);
+        assert_eq!(
+            (rejected.too_old, rejected.too_new, rejected.expired),
+            (7, 1, 6)
+        );
+        assert_eq!(rejected.total(), 10);
+    }
+
+    #[test]
+    fn rejected_events_ignore_out_of_range_indexes() {
+        let events: Vec<InputLogEvent> = (0..3).map(|i| event(i, "x")).collect();
+        let mut rejected = RejectedEvents::default();
+
+        rejected.add(&rejected_info(Some(10), Some(5), None), &events);
+
+        assert_eq!(rejected.too_old, 3);
+        assert_eq!(rejected.too_new, 0);
+        assert_eq!(rejected.total(), 3);
+    }
+
+    #[test]
+    fn truncates_large_messages_at_a_char_boundary() {
+        let message = "あ".repeat(MAX_EVENT_BYTES);
+        let truncated = truncate_message(message);
+
+        assert!(truncated.len() <= MAX_EVENT_BYTES - EVENT_OVERHEAD);
+        assert!(truncated.len() > MAX_EVENT_BYTES - EVENT_OVERHEAD - "あ".len());
+        assert_eq!(truncate_message("short".to_string()), "short");
+    }
+
+    #[test]
+    fn expected_sequence_token_from_the_error_message() {
+        assert_eq!(
+            expected_sequence_token(
+                "The given sequenceToken is invalid. The next expected sequenceToken is: 4956"
+            ),
+            Some("4956".to_string())
+        );
+        assert_eq!(
+            expected_sequence_token("The next expected sequenceToken is: null"),
+            None
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::path::PathBuf;
+
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{debug, info};
+
+use crate::cmd::get;
+use crate::cmd::get::cache::EventCache;
+use crate::cmd::get::highlight::Highlighter;
+use crate::cmd::get::printer::{self, Printer};
+use crate::errors;
+
+pub struct SearchOptions<'a> {
+    query: &'a str,
+    group_names: Vec<&'a str>,
+    start_time: Option<DateTime<Utc>>,
+    end_time: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+    use_prefix: bool,
+    color: printer::ColorMode,
+    cache_file: PathBuf,
+}
+
+impl<'a> From<&'a ArgMatches<'a>> for SearchOptions<'a> {
+    fn from(matches: &'a ArgMatches<'a>) -> Self {
+        SearchOptions {
+            query: matches.value_of("QUERY").unwrap(),
+            group_names: matches
+                .values_of("GROUP_NAME")
+                .map(|values| values.collect())
+                .unwrap_or_default(),
+            start_time: matches.value_of("START_TIME").map(get::from_jst_text),
+            end_time: matches.value_of("END_TIME").map(get::from_jst_text),
+            limit: get::matches_count(matches, "LIMIT"),
+            use_prefix: !matches.is_present("NO_PREFIX"),
+            color: matches
+                .value_of("COLOR")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(printer::ColorMode::Auto),
+            cache_file: matches
+                .value_of("CACHE_FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(EventCache::default_path),
+        }
+    }
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Search the events stored by 'get --cache' with a full-text query")
+        .arg(
+            Arg::with_name("QUERY")
+                .help("The SQLite FTS5 query (e.g. 'timeout', '\"connection reset\"', 'error AND db*')")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("GROUP_NAME")
+                .help("Search only the log group. Can be given multiple times.")
+                .short("g")
+                .long("group")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("GROUP_NAME"),
+        )
+        .arg(
+            Arg::with_name("START_TIME")
+                .help("The start of the time range")
+                .long("start-time")
+                .takes_value(true)
+                .validator(get::validate_jst_text)
+                .value_name("TIME"),
+        )
+        .arg(
+            Arg::with_name("END_TIME")
+                .help("The end of the time range")
+                .long("end-time")
+                .takes_value(true)
+                .validator(get::validate_jst_text)
+                .value_name("TIME"),
+        )
+        .arg(
+            Arg::with_name("LIMIT")
+                .help("Stop after printing NUM events.")
+                .short("n")
+                .long("limit")
+                .takes_value(true)
+                .validator(get::validate_count)
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("NO_PREFIX")
+                .help("Do not display the time and the source of the event at the begin of the line.")
+                .long("no-prefix"),
+        )
+        .arg(
+            Arg::with_name("COLOR")
+                .help("When to use colors. Matched terms of the query are highlighted.")
+                .long("color")
+                .takes_value(true)
+                .possible_values(&["always", "never", "auto"])
+                .value_name("WHEN"),
+        )
+        .arg(
+            Arg::with_name("CACHE_FILE")
+                .help("The SQLite database to search. Defaults to '$XDG_CACHE_HOME/razy-awslogs/events.sqlite3'.")
+                .long("cache-file")
+                .takes_value(true)
+                .value_name("PATH"),
+        )
+}
+
+/// 検索クエリから強調表示する語句を取り出す
+///
+/// FTS5は大文字小文字を区別しないので、強調表示も区別せずに行う。
+fn query_terms(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| c.is_whitespace() || "\"()*^:+".contains(c))
+        .filter(|term| !term.is_empty())
+        .filter(|term| !["AND", "OR", "NOT", "NEAR"].contains(term))
+        .map(regex::escape)
+        .collect()
+}
+
+fn create_printer(options: &SearchOptions) -> Result<Box<dyn Printer>, errors::Error> {
+    let enable_color = options.color.enabled();
+    let terms = query_terms(options.query);
+    let patterns: Vec<&str> = terms.iter().map(|s| s.as_str()).collect();
+    let highlighter = Highlighter::new(None, &patterns, true)?;
+
+    Ok(if options.use_prefix {
+        Box::new(printer::LogPrinter::new(
+            printer::stdout_sink(),
+            enable_color,
+            highlighter,
+        )) as Box<dyn Printer>
+    } else {
+        let highlighter = if enable_color {
+            Some(highlighter)
+        } else {
+            None
+        };
+        Box::new(printer::MessagePrinter::new(
+            printer::stdout_sink(),
+            highlighter,
+        )) as Box<dyn Printer>
+    })
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("parse search options");
+    let options = SearchOptions::from(matches);
+
+    debug!("open cache {}", options.cache_file.display());
+    let cache = EventCache::open_read_only(&options.cache_file)?;
+
+    info!("search cache");
+    let mut printer = create_printer(&options)?;
+    let result = cache.search(
+        options.query,
+        &options.group_names,
+        options.start_time.map(|t| t.timestamp_millis()),
+        options.end_time.map(|t| t.timestamp_millis()),
+        options.limit,
+        |events| {
+            debug!("print {} events", events.len());
+            printer.print_events(&events)?;
+            printer.flush()?;
+            Ok(())
+        },
+    );
+    match result {
+        Err(ref e) if e.kind() == errors::ErrorKind::BrokenPipe => {
+            debug!("output closed");
+            Ok(())
+        }
+        result => result,
+    }
+}
@@ -0,0 +1,375 @@
+use std::io::{self, Write};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use failure::{format_err, ResultExt};
+use log::info;
+use rusoto_logs::{
+    CloudWatchLogs, DeleteSubscriptionFilterError, DeleteSubscriptionFilterRequest,
+    DescribeSubscriptionFiltersError, DescribeSubscriptionFiltersRequest,
+    PutSubscriptionFilterError, PutSubscriptionFilterRequest, SubscriptionFilter,
+};
+use serde_derive::Serialize;
+
+use crate::cmd::get::printer;
+use crate::cmd::group::{self, group_arg, yes_arg};
+use crate::cmd::{self, Target};
+use crate::errors;
+
+fn filter_name_arg() -> Arg<'static, 'static> {
+    Arg::with_name("FILTER_NAME")
+        .help("The name of the subscription filter")
+        .short("n")
+        .long("name")
+        .required(true)
+        .takes_value(true)
+        .value_name("FILTER_NAME")
+}
+
+pub fn sub_command(s: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(s)
+        .about("Manage subscription filters streaming log events to Kinesis, Lambda or Firehose")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the subscription filters of the log groups")
+                .arg(
+                    group_arg()
+                        .required(false)
+                        .help("The name of the log group. If not provided, all the log groups are listed."),
+                )
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("List only the log groups whose name starts with PREFIX")
+                        .long("prefix")
+                        .takes_value(true)
+                        .conflicts_with("GROUP_NAME")
+                        .value_name("PREFIX"),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("The output format. 'json' writes one JSON object per line.")
+                        .long("output")
+                        .takes_value(true)
+                        .possible_values(&["table", "json"])
+                        .default_value("table")
+                        .value_name("FORMAT"),
+                )
+                .arg(
+                    Arg::with_name("COLOR")
+                        .help("When to use colors in the table header.")
+                        .long("color")
+                        .takes_value(true)
+                        .possible_values(&["always", "never", "auto"])
+                        .default_value("auto")
+                        .value_name("WHEN"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Create or update a subscription filter")
+                .arg(group_arg())
+                .arg(filter_name_arg())
+                .arg(
+                    Arg::with_name("FILTER_EXPRESSION")
+                        .help("The filter pattern. If not provided, all the events are delivered.")
+                        .short("f")
+                        .long("filter-pattern")
+                        .takes_value(true)
+                        .default_value("")
+                        .value_name("FILTER_EXPRESSION"),
+                )
+                .arg(
+                    Arg::with_name("DESTINATION_ARN")
+                        .help("The ARN of the Kinesis stream, Lambda function, Firehose delivery stream or logical destination")
+                        .long("destination-arn")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("ARN"),
+                )
+                .arg(
+                    Arg::with_name("DELIVERY_ROLE_ARN")
+                        .help("The ARN of the role CloudWatch Logs assumes to deliver the events. Not needed for Lambda and logical destinations.")
+                        .long("delivery-role-arn")
+                        .takes_value(true)
+                        .value_name("ROLE_ARN"),
+                )
+                .arg(
+                    Arg::with_name("DISTRIBUTION")
+                        .help("How to distribute the events to the shards of a Kinesis stream")
+                        .long("distribution")
+                        .takes_value(true)
+                        .possible_values(&["ByLogStream", "Random"])
+                        .value_name("DISTRIBUTION"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Delete a subscription filter")
+                .arg(group_arg())
+                .arg(filter_name_arg())
+                .arg(yes_arg()),
+        )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Records
+//
+////////////////////////////////////////////////////////////////////////////////
+
+/// `--output json` で1行に書き出すサブスクリプションフィルタ
+#[derive(Debug, Clone, Serialize)]
+struct SubscriptionRecord {
+    profile: String,
+    region: String,
+    group_name: String,
+    filter_name: String,
+    filter_pattern: String,
+    destination_type: String,
+    destination_arn: String,
+    role_arn: Option<String>,
+    distribution: Option<String>,
+    /// エポックからのミリ秒
+    creation_time: Option<i64>,
+}
+
+/// ARNのサービス名から配信先の種類を決める
+fn destination_type(arn: &str) -> String {
+    let service = arn.split(':').nth(2).unwrap_or("");
+    match service {
+        "kinesis" => "Kinesis".to_string(),
+        "lambda" => "Lambda".to_string(),
+        "firehose" => "Firehose".to_string(),
+        "logs" => "Destination".to_string(),
+        "" => "Unknown".to_string(),
+        service => service.to_string(),
+    }
+}
+
+impl SubscriptionRecord {
+    fn new(target: &Target, filter: SubscriptionFilter) -> Self {
+        let destination_arn = filter.destination_arn.unwrap_or_default();
+        SubscriptionRecord {
+            profile: target.profile.clone(),
+            region: target.region.name().to_string(),
+            group_name: filter.log_group_name.unwrap_or_default(),
+            filter_name: filter.filter_name.unwrap_or_default(),
+            filter_pattern: filter.filter_pattern.unwrap_or_default(),
+            destination_type: destination_type(destination_arn.as_str()),
+            destination_arn,
+            role_arn: filter.role_arn,
+            distribution: filter.distribution,
+            creation_time: filter.creation_time,
+        }
+    }
+}
+
+fn is_transient(e: &DescribeSubscriptionFiltersError) -> bool {
+    match e {
+        DescribeSubscriptionFiltersError::ServiceUnavailable(_)
+        | DescribeSubscriptionFiltersError::HttpDispatch(_) => true,
+        DescribeSubscriptionFiltersError::Unknown(res) => cmd::is_transient_response(res),
+        _ => false,
+    }
+}
+
+/// ロググループのサブスクリプションフィルタをすべて取得する
+///
+/// 全ロググループを順に問い合わせるとスロットリングされやすいため、一時的なエラーは待ってから再試行する。
+fn describe_subscription_filters(
+    target: &Target,
+    group_name: &str,
+) -> Result<Vec<SubscriptionFilter>, errors::Error> {
+    let mut filters = Vec::new();
+    let mut next_token = None;
+    loop {
+        let res = cmd::retry_with_backoff(is_transient, || {
+            target
+                .client
+                .describe_subscription_filters(DescribeSubscriptionFiltersRequest {
+                    log_group_name: group_name.to_string(),
+                    next_token: next_token.clone(),
+                    ..Default::default()
+                })
+                .sync()
+        })?;
+        filters.extend(res.subscription_filters.unwrap_or_default());
+
+        next_token = res.next_token;
+        if next_token.is_none() {
+            return Ok(filters);
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Output
+//
+////////////////////////////////////////////////////////////////////////////////
+
+fn print_json(records: &[SubscriptionRecord]) -> Result<(), errors::Error> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for record in records.iter() {
+        serde_json::to_writer(&mut out, record).context(errors::ErrorKind::Io)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn print_table(
+    records: &[SubscriptionRecord],
+    with_target: bool,
+    enable_color: bool,
+) -> Result<(), errors::Error> {
+    let mut headers = vec![
+        "GROUP",
+        "FILTER",
+        "TYPE",
+        "DESTINATION",
+        "PATTERN",
+        "CREATED",
+    ];
+    if with_target {
+        headers.insert(0, "TARGET");
+    }
+
+    let rows: Vec<Vec<String>> = records
+        .iter()
+        .map(|record| {
+            let mut row = vec![
+                record.group_name.clone(),
+                record.filter_name.clone(),
+                record.destination_type.clone(),
+                record.destination_arn.clone(),
+                record.filter_pattern.clone(),
+                record
+                    .creation_time
+                    .map(cmd::format_epoch_millis)
+                    .unwrap_or_default(),
+            ];
+            if with_target {
+                row.insert(0, format!("{}/{}", record.profile, record.region));
+            }
+            row
+        })
+        .collect();
+
+    cmd::print_table(&headers, &rows, enable_color)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Actions
+//
+////////////////////////////////////////////////////////////////////////////////
+
+fn list(targets: &[Target], matches: &ArgMatches) -> Result<(), errors::Error> {
+    let mut records = Vec::new();
+    for target in targets.iter() {
+        let group_names = match matches.value_of("GROUP_NAME") {
+            Some(group_name) => vec![group_name.to_string()],
+            None => group::describe_log_groups(&target.client, matches.value_of("PREFIX"))?
+                .into_iter()
+                .filter_map(|group| group.log_group_name)
+                .collect(),
+        };
+
+        for group_name in group_names.iter() {
+            for filter in describe_subscription_filters(target, group_name)? {
+                records.push(SubscriptionRecord::new(target, filter));
+            }
+        }
+    }
+
+    match matches.value_of("OUTPUT") {
+        Some("json") => print_json(&records),
+        _ => {
+            let color = matches
+                .value_of("COLOR")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(printer::ColorMode::Auto);
+            print_table(&records, targets.len() > 1, color.enabled())
+        }
+    }
+}
+
+fn put(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME").unwrap();
+    let filter_name = matches.value_of("FILTER_NAME").unwrap();
+    let destination_arn = matches.value_of("DESTINATION_ARN").unwrap();
+    target
+        .client
+        .put_subscription_filter(PutSubscriptionFilterRequest {
+            destination_arn: destination_arn.to_string(),
+            distribution: matches.value_of("DISTRIBUTION").map(|s| s.to_string()),
+            filter_name: filter_name.to_string(),
+            filter_pattern: matches.value_of("FILTER_EXPRESSION").unwrap().to_string(),
+            log_group_name: group_name.to_string(),
+            role_arn: matches.value_of("DELIVERY_ROLE_ARN").map(|s| s.to_string()),
+        })
+        .sync()?;
+    println!(
+        "Put {} on {} in {}: {} {}",
+        filter_name,
+        group_name,
+        target.label(),
+        destination_type(destination_arn),
+        destination_arn
+    );
+    Ok(())
+}
+
+fn delete(target: &Target, matches: &ArgMatches) -> Result<(), errors::Error> {
+    let group_name = matches.value_of("GROUP_NAME").unwrap();
+    let filter_name = matches.value_of("FILTER_NAME").unwrap();
+    let message = format!(
+        "Delete {} on {} in {}? The events are no longer delivered.",
+        filter_name,
+        group_name,
+        target.label()
+    );
+    if !cmd::confirm(message.as_str(), matches.is_present("YES"))? {
+        return Ok(());
+    }
+
+    target
+        .client
+        .delete_subscription_filter(DeleteSubscriptionFilterRequest {
+            filter_name: filter_name.to_string(),
+            log_group_name: group_name.to_string(),
+        })
+        .sync()?;
+    println!(
+        "Deleted {} on {} in {}",
+        filter_name,
+        group_name,
+        target.label()
+    );
+    Ok(())
+}
+
+pub fn run(targets: Vec<Target>, matches: &ArgMatches) -> Result<(), errors::Error> {
+    info!("invoke subscriptions commands");
+    match matches.subcommand() {
+        // 一覧は複数のプロファイル・リージョンをまとめて表示できる
+        ("list", Some(m)) => list(&targets, m),
+        ("put", Some(m)) => put(&cmd::single_target(targets)?, m),
+        ("delete", Some(m)) => delete(&cmd::single_target(targets)?, m),
+        (name, _) => Err(format_err!("Unknown subscriptions command: '{}'", name))
+            .context(errors::ErrorKind::NoSubCommand)?,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//
+// Errors
+//
+////////////////////////////////////////////////////////////////////////////////
+
+errors::impl_from_rusoto_error!(
+    DeleteSubscriptionFilterError,
+    DescribeSubscriptionFiltersError,
+    PutSubscriptionFilterError,
+);
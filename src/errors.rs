@@ -1,4 +1,5 @@
 use std::fmt::{self, Display, Formatter};
+use std::io;
 
 use failure::{Backtrace, Context, Fail};
 
@@ -10,14 +11,47 @@ pub enum ErrorKind {
     #[fail(display = "Argument error.")]
     InsufficientArguments,
 
+    #[fail(display = "Cache error.")]
+    Cache,
+
+    #[fail(display = "Checkpoint error.")]
+    Checkpoint,
+
+    #[fail(display = "Export task did not complete.")]
+    ExportTask,
+
+    #[fail(display = "Invalid archive file.")]
+    InvalidArchive,
+
+    #[fail(display = "Invalid filter pattern.")]
+    InvalidFilterPattern,
+
+    #[fail(display = "Invalid search query.")]
+    InvalidQuery,
+
+    #[fail(display = "Invalid region name.")]
+    InvalidRegion,
+
+    #[fail(display = "Invalid regular expression.")]
+    InvalidRegex,
+
     #[fail(display = "No subcommand given.")]
     NoSubCommand,
 
     #[fail(display = "Any rusoto error occurred")]
     Rusoto,
 
+    #[fail(display = "Any I/O error occurred")]
+    Io,
+
+    #[fail(display = "Output closed.")]
+    BrokenPipe,
+
     #[fail(display = "Any sync error occurred")]
     SyncChannel,
+
+    #[fail(display = "Any timer error occurred")]
+    Timer,
 }
 
 unsafe impl Send for ErrorKind {}
@@ -64,3 +98,47 @@ impl From<Context<ErrorKind>> for Error {
         Error { inner: context }
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        // パイプ先が先に終了した場合 (`| head` など) はエラーとして扱わずに終了させたい
+        if e.kind() == io::ErrorKind::BrokenPipe {
+            Error::from(ErrorKind::BrokenPipe)
+        } else {
+            Error::from(e.context(ErrorKind::Io))
+        }
+    }
+}
+
+/// rusotoの各APIのエラーを変換する
+///
+/// `Unknown` の場合は原因がわかるようにレスポンスの本文をそのままエラーの内容とする。
+macro_rules! impl_from_rusoto_error {
+    ($($error:ident),* $(,)*) => {
+        $(
+            impl From<$error> for $crate::errors::Error {
+                fn from(e: $error) -> Self {
+                    let context = match e {
+                        $error::Unknown(http_error) => {
+                            let body = String::from_utf8_lossy(&http_error.body).to_string();
+                            failure::ResultExt::context(
+                                Err::<(), failure::Error>(failure::format_err!("{}", body)),
+                                $crate::errors::ErrorKind::Rusoto,
+                            )
+                            .unwrap_err()
+                        }
+                        _ => failure::ResultExt::context(
+                            Err::<(), $error>(e),
+                            $crate::errors::ErrorKind::Rusoto,
+                        )
+                        .unwrap_err(),
+                    };
+
+                    $crate::errors::Error::from(context)
+                }
+            }
+        )*
+    };
+}
+
+pub(crate) use impl_from_rusoto_error;
@@ -23,6 +23,9 @@ fn main() {
 
     match app::main() {
         Ok(()) => (),
-        Err(e) => handle_error(e),
+        Err(e) => {
+            handle_error(e);
+            std::process::exit(1)
+        }
     }
 }